@@ -1,4 +1,6 @@
-use clap::{Parser, Subcommand};
+use crate::utils::privilege::Privilege;
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use std::path::PathBuf;
 
 #[derive(Parser)]
 #[command(name = "nx")]
@@ -19,6 +21,14 @@ pub enum Commands {
     Install {
         /// Package name to install
         package: String,
+
+        /// Wait for other nx operations to finish instead of failing
+        #[arg(long)]
+        wait: bool,
+
+        /// Edit the configuration even if its git repository has uncommitted changes
+        #[arg(long)]
+        allow_dirty: bool,
     },
 
     /// Remove packages
@@ -77,9 +87,8 @@ pub enum Commands {
     /// Configure settings
     #[command(alias = "c")]
     Config {
-        /// Allow unfree packages
-        #[arg(long)]
-        allow_unfree: Option<bool>,
+        #[command(flatten)]
+        options: ConfigOptions,
     },
 
     /// Modify NixOS system configuration
@@ -87,6 +96,35 @@ pub enum Commands {
     System {
         #[command(subcommand)]
         command: SystemCommands,
+
+        /// Wait for other nx operations to finish instead of failing
+        #[arg(long, global = true)]
+        wait: bool,
+
+        /// Edit the configuration even if its git repository has uncommitted changes
+        #[arg(long, global = true)]
+        allow_dirty: bool,
+    },
+
+    /// Show the log of operations nx performed
+    Log {
+        /// Number of most recent operations to show
+        #[arg(short = 'n', long, default_value_t = 20)]
+        limit: usize,
+    },
+
+    /// Revert the most recent reversible operation, or the given one
+    Undo {
+        /// Operation number as shown by 'nx log'
+        id: Option<u64>,
+
+        /// Wait for other nx operations to finish instead of failing
+        #[arg(long)]
+        wait: bool,
+
+        /// Edit the configuration even if its git repository has uncommitted changes
+        #[arg(long)]
+        allow_dirty: bool,
     },
 
     /// Generate Nix expression with prefilled hashes
@@ -101,6 +139,53 @@ pub enum Commands {
     },
 }
 
+#[derive(Args)]
+pub struct ConfigOptions {
+    /// Allow unfree packages
+    #[arg(long)]
+    pub allow_unfree: Option<bool>,
+
+    /// Number of configuration backups to keep (0 disables backups)
+    #[arg(long)]
+    pub backup_retention: Option<usize>,
+
+    /// Directory holding the NixOS configuration (default: /etc/nixos)
+    #[arg(long)]
+    pub nixos_root: Option<PathBuf>,
+
+    /// Configuration file to edit, relative to the root (default: configuration.nix)
+    #[arg(long)]
+    pub nixos_file: Option<PathBuf>,
+
+    /// Whether the configuration root is a flake (detected from flake.nix by default)
+    #[arg(long)]
+    pub nixos_flake: Option<bool>,
+
+    /// Flake host to build (default: this machine's hostname)
+    #[arg(long)]
+    pub nixos_host: Option<String>,
+
+    /// Send permanent system edits to nx-managed.nix instead of hand-written modules
+    #[arg(long)]
+    pub managed_module: Option<bool>,
+
+    /// NixOS options database (options.json) used to check and convert option values
+    #[arg(long)]
+    pub options_json: Option<PathBuf>,
+
+    /// How to gain root for rebuilds and configuration writes
+    #[arg(long, value_enum)]
+    pub privilege: Option<Privilege>,
+
+    /// Commit each configuration edit to git (the configuration root must be in a repository)
+    #[arg(long)]
+    pub git_commit: Option<bool>,
+
+    /// Tag the configuration commit with the generation number after rebuilds
+    #[arg(long)]
+    pub git_tag: Option<bool>,
+}
+
 #[derive(Subcommand)]
 pub enum SystemCommands {
     /// Add a package to system packages
@@ -112,6 +197,11 @@ pub enum SystemCommands {
         /// Make the change permanent (modifies configuration.nix)
         #[arg(short, long)]
         permanent: bool,
+
+        /// Module file to edit, relative to the configuration root
+        /// (default: the module that already configures related settings)
+        #[arg(long)]
+        file: Option<PathBuf>,
     },
 
     /// Enable a program or service
@@ -123,6 +213,11 @@ pub enum SystemCommands {
         /// Make the change permanent (modifies configuration.nix)
         #[arg(short, long)]
         permanent: bool,
+
+        /// Module file to edit, relative to the configuration root
+        /// (default: the module that already configures related settings)
+        #[arg(long)]
+        file: Option<PathBuf>,
     },
 
     /// Set a NixOS option
@@ -131,17 +226,266 @@ pub enum SystemCommands {
         /// Option path (e.g., "programs.fish.enable" or "networking.hostName")
         path: String,
 
-        /// Value to set (will be parsed as Nix expression)
+        /// Value to set, converted to the option's declared type (e.g. "yes" for
+        /// booleans, "a,b" for lists, strings are quoted)
         value: String,
 
         /// Make the change permanent (modifies configuration.nix)
         #[arg(short, long)]
         permanent: bool,
+
+        /// Module file to edit, relative to the configuration root
+        /// (default: the module that already configures related settings)
+        #[arg(long)]
+        file: Option<PathBuf>,
+
+        /// Write the value as a Nix expression without looking up the option's type
+        #[arg(long)]
+        raw: bool,
     },
 
-    /// Apply temporary changes
+    /// Add an element to a list option, unless it is already there
+    AddTo {
+        /// Option path (e.g., "users.users.alice.extraGroups")
+        path: String,
+
+        /// Element to add, converted to the list's element type
+        element: String,
+
+        /// Make the change permanent (modifies configuration.nix)
+        #[arg(short, long)]
+        permanent: bool,
+
+        /// Module file to edit, relative to the configuration root
+        /// (default: the module that already configures related settings)
+        #[arg(long)]
+        file: Option<PathBuf>,
+
+        /// Use the element as a Nix expression without looking up the option's type
+        #[arg(long)]
+        raw: bool,
+    },
+
+    /// Remove every occurrence of an element from a list option
+    RemoveFrom {
+        /// Option path (e.g., "users.users.alice.extraGroups")
+        path: String,
+
+        /// Element to remove, converted to the list's element type
+        element: String,
+
+        /// Make the change permanent (modifies configuration.nix)
+        #[arg(short, long)]
+        permanent: bool,
+
+        /// Use the element as a Nix expression without looking up the option's type
+        #[arg(long)]
+        raw: bool,
+    },
+
+    /// Import a JSON, TOML or YAML file as the attribute set of an option
+    ImportSettings {
+        /// Option path (e.g., "services.grafana.settings")
+        path: String,
+
+        /// Settings file (.json, .toml, .yaml or .yml)
+        settings: PathBuf,
+
+        /// Make the change permanent (modifies configuration.nix)
+        #[arg(short, long)]
+        permanent: bool,
+
+        /// Module file to edit, relative to the configuration root
+        /// (default: the module that already configures related settings)
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+
+    /// Remove a package from system packages
+    #[command(alias = "rm-pkg")]
+    RemovePackage {
+        /// Package to remove
+        package: String,
+
+        /// Make the change permanent (modifies configuration.nix)
+        #[arg(short, long)]
+        permanent: bool,
+    },
+
+    /// Disable a program or service
+    #[command(alias = "dis")]
+    Disable {
+        /// Program or service to disable (e.g., "programs.fish" or "services.docker")
+        program: String,
+
+        /// Remove the enable binding instead of setting it to false
+        #[arg(short, long)]
+        remove: bool,
+
+        /// Make the change permanent (modifies configuration.nix)
+        #[arg(short, long)]
+        permanent: bool,
+    },
+
+    /// Remove a NixOS option binding
+    #[command(alias = "unset")]
+    UnsetOption {
+        /// Option path (e.g., "networking.hostName")
+        path: String,
+
+        /// Make the change permanent (modifies configuration.nix)
+        #[arg(short, long)]
+        permanent: bool,
+    },
+
+    /// Show the current value of a NixOS option
+    #[command(alias = "get")]
+    GetOption {
+        /// Option path (e.g., "services.openssh.enable")
+        path: String,
+
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Apply temporary changes until the next reboot (nixos-rebuild test)
     Apply,
 
+    /// Make temporary changes permanent by folding them into configuration.nix
+    Commit,
+
+    /// Discard temporary changes
+    Discard,
+
     /// Show pending changes
-    Show,
+    Show {
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+    },
+
+    /// Export the evaluated system configuration
+    Export {
+        /// Option prefix to export (e.g., "services.openssh"; default: everything)
+        prefix: Option<String>,
+
+        /// Output as JSON
+        #[arg(long)]
+        json: bool,
+
+        /// Include pending temporary changes
+        #[arg(long)]
+        pending: bool,
+    },
+
+    /// Edit the configuration in $EDITOR, checking it before keeping the result
+    Edit {
+        /// Module file to edit, relative to the configuration root
+        /// (default: the configuration file)
+        #[arg(long)]
+        file: Option<PathBuf>,
+    },
+
+    /// Rebuild the system from the configuration
+    Rebuild {
+        /// What to do with the new system
+        #[arg(value_enum, default_value_t = RebuildMode::Switch)]
+        mode: RebuildMode,
+    },
+
+    /// List system generations
+    History,
+
+    /// Switch back to the previous generation, or to the given one
+    Rollback {
+        /// Generation number (see 'nx sys history')
+        generation: Option<u32>,
+    },
+
+    /// Compare the packages of two generations
+    GenDiff {
+        /// Older generation number
+        from: u32,
+
+        /// Newer generation number
+        to: u32,
+    },
+
+    /// Show configuration changes since the last rebuild and pending changes as diffs
+    Diff {
+        /// Also list the options whose values change, evaluating pending changes
+        #[arg(long)]
+        eval: bool,
+    },
+
+    /// List backups of configuration files taken before nx modified them
+    Backups,
+
+    /// Restore a configuration file from a backup
+    RestoreBackup {
+        /// Backup ID as shown by 'nx sys backups'
+        id: String,
+    },
+
+    /// Save every file in the configuration directory and the current
+    /// generation under a label
+    Snapshot {
+        /// Name of the snapshot
+        label: String,
+
+        /// Replace an existing snapshot with the same label
+        #[arg(long)]
+        force: bool,
+    },
+
+    /// List configuration snapshots
+    Snapshots,
+
+    /// Write back the configuration files saved in a snapshot
+    Restore {
+        /// Label of the snapshot, as shown by 'nx sys snapshots'
+        label: String,
+
+        /// Rebuild and switch to the restored configuration
+        #[arg(long)]
+        rebuild: bool,
+    },
+
+    /// Drop a single pending change by its number in 'nx sys show'
+    Drop {
+        /// Number of the change to drop
+        number: usize,
+    },
+}
+
+/// The nixos-rebuild actions nx can run.
+#[derive(ValueEnum, Clone, Copy, PartialEq, Eq, Debug)]
+pub enum RebuildMode {
+    /// Build, activate and make the boot default
+    Switch,
+    /// Build and make the boot default without activating
+    Boot,
+    /// Build and activate without making the boot default
+    Test,
+    /// Only build
+    Build,
+    /// Build and show what activation would change
+    DryActivate,
+    /// Only show what would be built or downloaded
+    DryBuild,
+}
+
+impl RebuildMode {
+    /// The nixos-rebuild action name.
+    pub fn action(self) -> &'static str {
+        match self {
+            RebuildMode::Switch => "switch",
+            RebuildMode::Boot => "boot",
+            RebuildMode::Test => "test",
+            RebuildMode::Build => "build",
+            RebuildMode::DryActivate => "dry-activate",
+            RebuildMode::DryBuild => "dry-build",
+        }
+    }
 }
@@ -1,16 +1,120 @@
+use crate::cli::ConfigOptions;
 use crate::config::store::Config;
+use std::path::{Path, PathBuf};
+
+pub fn execute(options: ConfigOptions, config: &mut Config) -> i32 {
+    let ConfigOptions {
+        allow_unfree,
+        backup_retention,
+        nixos_root,
+        nixos_file,
+        nixos_flake,
+        nixos_host,
+        managed_module,
+        options_json,
+        privilege,
+        git_commit,
+        git_tag,
+    } = options;
+
+    if allow_unfree.is_none()
+        && backup_retention.is_none()
+        && nixos_root.is_none()
+        && nixos_file.is_none()
+        && nixos_flake.is_none()
+        && nixos_host.is_none()
+        && managed_module.is_none()
+        && options_json.is_none()
+        && privilege.is_none()
+        && git_commit.is_none()
+        && git_tag.is_none()
+    {
+        println!("Current settings:");
+        println!("  Allow unfree packages: {}", config.allow_unfree);
+        println!("  Configuration backups kept: {}", config.backup_retention);
+        println!(
+            "  NixOS configuration: {}",
+            config.nixos_config_path().display()
+        );
+        if config.is_flake() {
+            println!("  Flake: {}", config.flake_ref());
+        } else {
+            println!("  Flake: no");
+        }
+        println!("  Use nx-managed module: {}", config.managed_module);
+        match &config.options_json {
+            Some(path) => println!("  Options database: {}", path.display()),
+            None => println!("  Options database: built from the configuration"),
+        }
+        println!("  Privilege escalation: {}", config.privilege);
+        println!("  Commit edits to git: {}", config.git_commit);
+        println!("  Tag generations in git: {}", config.git_tag);
+        return 0;
+    }
 
-pub fn execute(allow_unfree: Option<bool>, config: &mut Config) -> i32 {
     if let Some(allow) = allow_unfree {
         config.allow_unfree = allow;
-        config.save();
         println!(
             "Updated unfree package setting: {}",
             if allow { "allowed" } else { "disallowed" }
         );
-    } else {
-        println!("Current settings:");
-        println!("  Allow unfree packages: {}", config.allow_unfree);
     }
+    if let Some(retention) = backup_retention {
+        config.backup_retention = retention;
+        println!("Updated configuration backups kept: {}", retention);
+    }
+    if let Some(root) = nixos_root {
+        config.nixos_root = absolute(&root);
+        println!(
+            "Updated NixOS configuration root: {}",
+            config.nixos_root.display()
+        );
+    }
+    if let Some(file) = nixos_file {
+        config.nixos_file = file;
+        println!(
+            "Updated NixOS configuration file: {}",
+            config.nixos_config_path().display()
+        );
+    }
+    if let Some(flake) = nixos_flake {
+        config.nixos_flake = Some(flake);
+        println!("Updated flake setting: {}", flake);
+    }
+    if let Some(host) = nixos_host {
+        config.nixos_host = Some(host);
+        println!("Updated flake host: {}", config.flake_host());
+    }
+    if let Some(managed) = managed_module {
+        config.managed_module = managed;
+        println!("Updated nx-managed module setting: {}", managed);
+    }
+    if let Some(path) = options_json {
+        let path = absolute(&path);
+        println!("Updated options database: {}", path.display());
+        config.options_json = Some(path);
+    }
+    if let Some(privilege) = privilege {
+        config.privilege = privilege;
+        println!("Updated privilege escalation: {}", privilege);
+    }
+    if let Some(commit) = git_commit {
+        config.git_commit = commit;
+        println!("Updated git commit setting: {}", commit);
+    }
+    if let Some(tag) = git_tag {
+        config.git_tag = tag;
+        println!("Updated git tag setting: {}", tag);
+    }
+    config.save();
     0
 }
+
+fn absolute(path: &Path) -> PathBuf {
+    if path.is_absolute() {
+        return path.to_path_buf();
+    }
+    std::env::current_dir()
+        .map(|dir| dir.join(path))
+        .unwrap_or_else(|_| path.to_path_buf())
+}
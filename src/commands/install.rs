@@ -1,10 +1,38 @@
-use crate::commands::system::add_system_package;
+use crate::commands::log::Recorder;
+use crate::commands::system::{acquire_lock, add_system_package, nixos_rebuild, with_git_commit};
 use crate::config::store::Config;
 use crate::utils::command::run_command;
 use crate::utils::package::is_unfree_package;
 use std::process::Command;
 
-pub fn install(package: &str, passthrough_args: &[String], config: &Config) -> i32 {
+pub fn install(
+    package: &str,
+    wait: bool,
+    allow_dirty: bool,
+    passthrough_args: &[String],
+    config: &Config,
+) -> i32 {
+    // Held until the rebuild finishes so no other nx run edits the config meanwhile
+    let _lock = match acquire_lock(wait, config) {
+        Ok(lock) => lock,
+        Err(e) => {
+            eprintln!("Failed to start installation: {}", e);
+            return 1;
+        }
+    };
+
+    let recorder = Recorder::new(true, config);
+    let code = install_package(package, allow_dirty, passthrough_args, config);
+    recorder.finish(code, None, config);
+    code
+}
+
+fn install_package(
+    package: &str,
+    allow_dirty: bool,
+    passthrough_args: &[String],
+    config: &Config,
+) -> i32 {
     // Check if package is unfree
     if is_unfree_package(package) {
         if !config.allow_unfree {
@@ -14,33 +42,38 @@ pub fn install(package: &str, passthrough_args: &[String], config: &Config) -> i
         }
     }
 
-    // First, install to profile for immediate use
-    println!("Installing {} to profile for immediate use...", package);
-    let cmd = format!("nixpkgs#{}", package);
-    let profile_result = run_command(
-        "nix",
-        &["profile", "install", &cmd],
-        passthrough_args,
-        config,
-    );
+    let message = format!("add package {}", package);
+    let added = with_git_commit(config, allow_dirty, &message, || {
+        // First, install to profile for immediate use
+        println!("Installing {} to profile for immediate use...", package);
+        let cmd = format!("nixpkgs#{}", package);
+        let profile_result = run_command(
+            "nix",
+            &["profile", "install", &cmd],
+            passthrough_args,
+            config,
+        );
 
-    if profile_result.is_err() {
-        eprintln!("Failed to install package to profile");
-        return 1;
-    }
+        if profile_result.is_err() {
+            eprintln!("Failed to install package to profile");
+            return 1;
+        }
 
-    // Add to system packages
-    println!("Adding {} to system packages...", package);
-    if add_system_package(package) != 0 {
-        eprintln!("Failed to add package to system configuration");
+        // Add to system packages
+        println!("Adding {} to system packages...", package);
+        if add_system_package(package, None, config) != 0 {
+            eprintln!("Failed to add package to system configuration");
+            return 1;
+        }
+        0
+    });
+    if added != 0 {
         return 1;
     }
 
     // Run nixos-rebuild switch
     println!("Rebuilding system configuration...");
-    let rebuild_result = Command::new("sudo")
-        .args(["nixos-rebuild", "switch"])
-        .status();
+    let rebuild_result = nixos_rebuild(config, "switch", &[]);
 
     match rebuild_result {
         Ok(status) if status.success() => {
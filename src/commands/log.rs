@@ -0,0 +1,382 @@
+use crate::cli::Commands;
+use crate::commands::system::{
+    acquire_lock, current_generation, find_backup, get_configuration_files, get_pending_files,
+    replace_config_file, switch_hint, with_git_commit,
+};
+use crate::config::audit::{self, Entry, FileChange, GenerationChange, ProfileChange};
+use crate::config::store::Config;
+use crate::utils::command::run_command;
+use crate::utils::profile::{self, ProfileState};
+use chrono::Utc;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+/// Whether `command` changes anything and is recorded around its whole run.
+/// Commands taking the operation lock (`nx install`, editing `nx sys`
+/// commands and `nx undo`) record themselves once they hold it, so edits
+/// another nx run makes while they wait are not logged as theirs.
+pub fn should_record(command: &Commands) -> bool {
+    matches!(
+        command,
+        Commands::Remove { .. }
+            | Commands::Update { .. }
+            | Commands::GarbageCollect { .. }
+            | Commands::Optimize { .. }
+    )
+}
+
+/// Captures the state an operation may change, to log the difference once it
+/// finishes.
+pub struct Recorder {
+    command: Vec<String>,
+    /// Content hash of each configuration and pending-change file
+    files: BTreeMap<PathBuf, String>,
+    /// Content of the pending-change files, which have no backups
+    pending: BTreeMap<PathBuf, String>,
+    profile: Option<ProfileState>,
+    generation: Option<u32>,
+}
+
+impl Recorder {
+    pub fn start(command: &Commands, config: &Config) -> Self {
+        let tracks_profile = matches!(
+            command,
+            Commands::Install { .. } | Commands::Remove { .. } | Commands::Update { .. }
+        );
+        Recorder::new(tracks_profile, config)
+    }
+
+    /// Captures the current state; `tracks_profile` also records the nix
+    /// profile.
+    pub fn new(tracks_profile: bool, config: &Config) -> Self {
+        Recorder {
+            command: std::env::args().collect(),
+            files: hash_files(config),
+            pending: get_pending_files(config)
+                .into_iter()
+                .filter_map(|path| {
+                    let content = fs::read_to_string(&path).ok()?;
+                    Some((path, content))
+                })
+                .collect(),
+            profile: if tracks_profile {
+                profile::current()
+            } else {
+                None
+            },
+            generation: current_generation(),
+        }
+    }
+
+    /// Appends the operation to the log. Failing to write the log only warns,
+    /// as the operation itself already happened.
+    pub fn finish(self, exit_code: i32, undoes: Option<u64>, config: &Config) {
+        let after = hash_files(config);
+        let mut paths: Vec<&PathBuf> = self.files.keys().chain(after.keys()).collect();
+        paths.sort();
+        paths.dedup();
+        let files = paths
+            .into_iter()
+            .filter(|path| self.files.get(*path) != after.get(*path))
+            .map(|path| FileChange {
+                path: path.clone(),
+                before: self.files.get(path).cloned(),
+                after: after.get(path).cloned(),
+                previous: self.pending.get(path).cloned(),
+            })
+            .collect();
+
+        let profile = self.profile.and_then(|before| {
+            let after = profile::current()?;
+            (before.generation != after.generation).then(|| ProfileChange {
+                generation_before: before.generation,
+                generation_after: after.generation,
+                installed: after
+                    .packages
+                    .difference(&before.packages)
+                    .cloned()
+                    .collect(),
+                removed: before
+                    .packages
+                    .difference(&after.packages)
+                    .cloned()
+                    .collect(),
+            })
+        });
+        let generation_after = current_generation();
+        let generation = (generation_after != self.generation).then_some(GenerationChange {
+            before: self.generation,
+            after: generation_after,
+        });
+
+        let entry = Entry {
+            id: 0,
+            timestamp: Utc::now(),
+            user: std::env::var("USER")
+                .or_else(|_| std::env::var("LOGNAME"))
+                .unwrap_or_else(|_| "unknown".to_string()),
+            command: self.command,
+            files,
+            profile,
+            generation,
+            exit_code,
+            undoes,
+        };
+        if let Err(e) = audit::append(entry) {
+            eprintln!("Warning: could not write the operation log: {}", e);
+        }
+    }
+}
+
+fn hash_files(config: &Config) -> BTreeMap<PathBuf, String> {
+    get_configuration_files(config)
+        .into_iter()
+        .chain(get_pending_files(config))
+        .filter_map(|path| {
+            let hash = audit::content_hash(&fs::read(&path).ok()?);
+            Some((path, hash))
+        })
+        .collect()
+}
+
+pub fn execute(limit: usize) -> i32 {
+    let entries = match audit::entries() {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Failed to read the operation log: {}", e);
+            return 1;
+        }
+    };
+    if entries.is_empty() {
+        println!("No operations logged.");
+        return 0;
+    }
+
+    let undone: BTreeMap<u64, u64> = entries
+        .iter()
+        .filter_map(|entry| entry.undoes.map(|target| (target, entry.id)))
+        .collect();
+    println!("Operations (newest last):");
+    for entry in &entries[entries.len().saturating_sub(limit)..] {
+        let mut header = format!(
+            "{:>4}  {}  {}  {}",
+            entry.id,
+            entry
+                .timestamp
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M:%S"),
+            entry.user,
+            command_line(entry)
+        );
+        if entry.exit_code != 0 {
+            header.push_str(&format!("  [exit {}]", entry.exit_code));
+        }
+        if let Some(by) = undone.get(&entry.id) {
+            header.push_str(&format!("  [undone by {}]", by));
+        }
+        println!("{}", header);
+
+        for file in &entry.files {
+            let marker = match (&file.before, &file.after) {
+                (None, _) => "A",
+                (_, None) => "D",
+                _ => "M",
+            };
+            println!("        {} {}", marker, file.path.display());
+        }
+        if let Some(profile) = &entry.profile {
+            for package in &profile.installed {
+                println!("        + profile: {}", package);
+            }
+            for package in &profile.removed {
+                println!("        - profile: {}", package);
+            }
+        }
+        if let Some(generation) = &entry.generation {
+            println!(
+                "        system generation {} -> {}",
+                format_generation(generation.before),
+                format_generation(generation.after)
+            );
+        }
+    }
+    0
+}
+
+fn command_line(entry: &Entry) -> String {
+    let mut args = entry.command.clone();
+    if let Some(program) = args.first_mut() {
+        // Show `nx` rather than the full path it was run as
+        if let Some(name) = program.rsplit('/').next() {
+            *program = name.to_string();
+        }
+    }
+    args.join(" ")
+}
+
+fn format_generation(generation: Option<u32>) -> String {
+    generation.map_or("?".to_string(), |generation| generation.to_string())
+}
+
+/// Whether undoing `entry` would change anything.
+fn is_reversible(entry: &Entry) -> bool {
+    !entry.files.is_empty()
+        || entry.profile.as_ref().is_some_and(|profile| {
+            profile.generation_before.is_some() || !profile.installed.is_empty()
+        })
+}
+
+pub fn undo(id: Option<u64>, wait: bool, allow_dirty: bool, config: &Config) -> i32 {
+    let _lock = match acquire_lock(wait, config) {
+        Ok(lock) => lock,
+        Err(e) => {
+            eprintln!("Failed to start undo: {}", e);
+            return 1;
+        }
+    };
+    let entries = match audit::entries() {
+        Ok(entries) => entries,
+        Err(e) => {
+            eprintln!("Failed to read the operation log: {}", e);
+            return 1;
+        }
+    };
+    let is_undone = |id: u64| entries.iter().any(|entry| entry.undoes == Some(id));
+
+    let entry =
+        match id {
+            Some(id) => match entries.iter().find(|entry| entry.id == id) {
+                Some(entry) if is_undone(id) => {
+                    eprintln!("Operation {} was already undone.", entry.id);
+                    return 1;
+                }
+                Some(entry) if !is_reversible(entry) => {
+                    eprintln!("Operation {} changed nothing nx can undo.", entry.id);
+                    return 1;
+                }
+                Some(entry) => entry,
+                None => {
+                    eprintln!("No operation {}. Run 'nx log' to list them.", id);
+                    return 1;
+                }
+            },
+            None => match entries.iter().rev().find(|entry| {
+                entry.undoes.is_none() && !is_undone(entry.id) && is_reversible(entry)
+            }) {
+                Some(entry) => entry,
+                None => {
+                    println!("Nothing to undo.");
+                    return 0;
+                }
+            },
+        };
+
+    // Work out every file's previous content before touching anything, so a
+    // file that cannot be reverted leaves the rest alone too
+    let mut reverts = Vec::new();
+    for file in &entry.files {
+        let current = fs::read(&file.path)
+            .ok()
+            .map(|content| audit::content_hash(&content));
+        if current != file.after {
+            eprintln!(
+                "{} has changed since operation {}; not undoing.",
+                file.path.display(),
+                entry.id
+            );
+            return 1;
+        }
+        let previous = match (&file.before, &file.previous) {
+            (None, _) => None,
+            (Some(_), Some(content)) => Some(content.clone()),
+            (Some(hash), None) => match find_backup(config, &file.path, hash) {
+                Ok(Some(content)) => Some(content),
+                Ok(None) => {
+                    eprintln!(
+                        "No backup of {} from before operation {}; it cannot be undone.",
+                        file.path.display(),
+                        entry.id
+                    );
+                    return 1;
+                }
+                Err(e) => {
+                    eprintln!("Failed to read backups: {}", e);
+                    return 1;
+                }
+            },
+        };
+        reverts.push((file, previous));
+    }
+
+    let recorder = Recorder::new(entry.profile.is_some(), config);
+    let message = format!("undo operation {}", entry.id);
+    let code = with_git_commit(config, allow_dirty, &message, || {
+        for (file, previous) in &reverts {
+            let path = &file.path;
+            let result = match previous {
+                // Pending-change files are nx's own and are not backed up
+                Some(content) if file.previous.is_some() => {
+                    config.privilege.write(path, content.as_bytes())
+                }
+                Some(content) => replace_config_file(config, path, content),
+                None => config.privilege.remove_file(path),
+            };
+            if let Err(e) = result {
+                eprintln!("Failed to revert {}: {}", path.display(), e);
+                return 1;
+            }
+            println!("Reverted {}", path.display());
+        }
+        match &entry.profile {
+            Some(change) => undo_profile(change, config),
+            None => 0,
+        }
+    });
+    recorder.finish(code, Some(entry.id), config);
+
+    if code == 0 {
+        println!("Undid operation {}: {}", entry.id, command_line(entry));
+        if !reverts.is_empty() {
+            println!("{}", switch_hint(config));
+        }
+    }
+    code
+}
+
+/// Rolls the profile back to the generation before the change if nothing
+/// changed it since, otherwise removes the packages the change installed.
+fn undo_profile(change: &ProfileChange, config: &Config) -> i32 {
+    let current = profile::current().and_then(|state| state.generation);
+    let result = match change.generation_before {
+        Some(before) if current.is_some() && current == change.generation_after => run_command(
+            "nix",
+            &["profile", "rollback", "--to", &before.to_string()],
+            &[],
+            config,
+        ),
+        _ if !change.installed.is_empty() => {
+            let mut args = vec!["profile", "remove"];
+            args.extend(change.installed.iter().map(String::as_str));
+            run_command("nix", &args, &[], config)
+        }
+        _ => {
+            eprintln!("The profile has changed since; its changes cannot be undone.");
+            return 1;
+        }
+    };
+    match result {
+        Ok(status) if status.success() => {
+            println!("Reverted the profile changes.");
+            0
+        }
+        Ok(status) => {
+            eprintln!("Reverting the profile failed with {}.", status);
+            1
+        }
+        Err(e) => {
+            eprintln!("Failed to run nix: {}", e);
+            1
+        }
+    }
+}
@@ -3,6 +3,7 @@ pub mod gc;
 pub mod generate;
 pub mod install;
 pub mod list;
+pub mod log;
 pub mod optimize;
 pub mod remove;
 pub mod search;
@@ -14,6 +15,8 @@ pub use self::gc::execute as gc_execute;
 pub use self::generate::execute as generate_execute;
 pub use self::install::install;
 pub use self::list::execute as list_execute;
+pub use self::log::execute as log_execute;
+pub use self::log::undo;
 pub use self::optimize::execute as optimize_execute;
 pub use self::remove::execute as remove_execute;
 pub use self::search::execute as search_execute;
@@ -0,0 +1,210 @@
+//! Timestamped copies of configuration files taken before nx modifies them.
+
+use crate::utils::privilege::Privilege;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+
+const INDEX_FILE: &str = "index.json";
+// Copies of the configuration files as of the last rebuild nx ran
+const REBUILT_DIR: &str = "rebuilt";
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct Backup {
+    pub id: String,
+    pub original: PathBuf,
+    pub created: DateTime<Utc>,
+}
+
+/// The configuration files recorded at a rebuild; file `n` of the list is
+/// stored as `rebuilt/<n>`.
+#[derive(Serialize, Deserialize)]
+pub struct RebuiltState {
+    pub created: DateTime<Utc>,
+    pub files: Vec<PathBuf>,
+}
+
+/// Backups may hold secrets, so the directory is private to its owner and
+/// every file in it readable by it only.
+pub struct BackupStore {
+    dir: PathBuf,
+    privilege: Privilege,
+}
+
+impl BackupStore {
+    pub fn new(dir: PathBuf, privilege: Privilege) -> Self {
+        BackupStore { dir, privilege }
+    }
+
+    /// Lists backups, oldest first.
+    pub fn list(&self) -> io::Result<Vec<Backup>> {
+        match self.read_file(&self.dir.join(INDEX_FILE)) {
+            Ok(content) => serde_json::from_str(&content).map_err(io::Error::from),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn find(&self, id: &str) -> io::Result<Option<Backup>> {
+        Ok(self.list()?.into_iter().find(|backup| backup.id == id))
+    }
+
+    pub fn read(&self, backup: &Backup) -> io::Result<String> {
+        self.read_file(&self.dir.join(&backup.id))
+    }
+
+    fn read_file(&self, path: &Path) -> io::Result<String> {
+        String::from_utf8(self.privilege.read(path)?)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Stores `content` as a backup of `original`, then prunes all but the
+    /// newest `retention` backups. A retention of 0 disables backups.
+    pub fn create(
+        &self,
+        original: &Path,
+        content: &str,
+        retention: usize,
+    ) -> io::Result<Option<Backup>> {
+        if retention == 0 {
+            return Ok(None);
+        }
+        self.privilege.create_private_dir(&self.dir)?;
+
+        let mut backups = self.list()?;
+        let created = Utc::now();
+        let backup = Backup {
+            id: self.unique_id(&created.format("%Y%m%d-%H%M%S-%3f").to_string(), &backups),
+            original: original.to_path_buf(),
+            created,
+        };
+        self.privilege
+            .write_private(&self.dir.join(&backup.id), content.as_bytes())?;
+
+        backups.push(backup.clone());
+        let excess = backups.len().saturating_sub(retention);
+        for old in backups.drain(..excess) {
+            let _ = self.privilege.remove_file(&self.dir.join(&old.id));
+        }
+        self.privilege.write_private(
+            &self.dir.join(INDEX_FILE),
+            serde_json::to_string_pretty(&backups)?.as_bytes(),
+        )?;
+        Ok(Some(backup))
+    }
+
+    /// Backups taken within the same millisecond, e.g. of several files in
+    /// one operation, get `-1`, `-2`, ... appended to the timestamp.
+    fn unique_id(&self, timestamp: &str, backups: &[Backup]) -> String {
+        let taken =
+            |id: &str| backups.iter().any(|backup| backup.id == id) || self.dir.join(id).exists();
+        let mut id = timestamp.to_string();
+        let mut n = 0;
+        while taken(&id) {
+            n += 1;
+            id = format!("{}-{}", timestamp, n);
+        }
+        id
+    }
+
+    /// Records `files` (path and content) as the state of the last rebuild,
+    /// replacing the previous record.
+    pub fn record_rebuilt(&self, files: &[(PathBuf, String)]) -> io::Result<()> {
+        let dir = self.dir.join(REBUILT_DIR);
+        match self.privilege.remove_dir_all(&dir) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        self.privilege.create_private_dir(&self.dir)?;
+        self.privilege.create_private_dir(&dir)?;
+
+        for (i, (_, content)) in files.iter().enumerate() {
+            self.privilege
+                .write_private(&dir.join(i.to_string()), content.as_bytes())?;
+        }
+        let state = RebuiltState {
+            created: Utc::now(),
+            files: files.iter().map(|(path, _)| path.clone()).collect(),
+        };
+        self.privilege.write_private(
+            &dir.join(INDEX_FILE),
+            serde_json::to_string_pretty(&state)?.as_bytes(),
+        )
+    }
+
+    /// Returns the state recorded at the last rebuild, if any.
+    pub fn rebuilt(&self) -> io::Result<Option<RebuiltState>> {
+        match self.read_file(&self.dir.join(REBUILT_DIR).join(INDEX_FILE)) {
+            Ok(content) => serde_json::from_str(&content)
+                .map(Some)
+                .map_err(io::Error::from),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Reads the content `original` had at the last rebuild.
+    pub fn read_rebuilt(
+        &self,
+        state: &RebuiltState,
+        original: &Path,
+    ) -> io::Result<Option<String>> {
+        match state.files.iter().position(|path| path == original) {
+            Some(i) => self
+                .read_file(&self.dir.join(REBUILT_DIR).join(i.to_string()))
+                .map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn backups_in_the_same_millisecond_get_distinct_ids() {
+        let dir = std::env::temp_dir().join(format!("nx-backup-test-{}", std::process::id()));
+        let store = BackupStore::new(dir.clone(), Privilege::None);
+        let original = Path::new("/etc/nixos/configuration.nix");
+        let ids: Vec<String> = (0..20)
+            .map(|i| {
+                let content = i.to_string();
+                store.create(original, &content, 50).unwrap().unwrap().id
+            })
+            .collect();
+
+        let backups = store.list().unwrap();
+        assert_eq!(backups.len(), 20);
+        for (i, id) in ids.iter().enumerate() {
+            let backup = store.find(id).unwrap().unwrap();
+            assert_eq!(store.read(&backup).unwrap(), i.to_string());
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn backups_are_private() {
+        let dir = std::env::temp_dir().join(format!("nx-backup-mode-test-{}", std::process::id()));
+        let store = BackupStore::new(dir.clone(), Privilege::None);
+        let backup = store
+            .create(Path::new("/etc/nixos/secrets.nix"), "{ }", 5)
+            .unwrap()
+            .unwrap();
+        store
+            .record_rebuilt(&[(PathBuf::from("/etc/nixos/secrets.nix"), "{ }".to_string())])
+            .unwrap();
+
+        let mode = |path: PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(dir.clone()), 0o700);
+        assert_eq!(mode(dir.join(&backup.id)), 0o600);
+        assert_eq!(mode(dir.join(INDEX_FILE)), 0o600);
+        assert_eq!(mode(dir.join(REBUILT_DIR)), 0o700);
+        assert_eq!(mode(dir.join(REBUILT_DIR).join("0")), 0o600);
+        assert_eq!(mode(dir.join(REBUILT_DIR).join(INDEX_FILE)), 0o600);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,54 @@
+//! Textual and option-level differences between configuration versions.
+
+use super::editor;
+use similar::TextDiff;
+use std::collections::BTreeMap;
+
+/// Lines of unchanged context shown around each change.
+const CONTEXT_LINES: usize = 3;
+
+/// Renders a unified diff from `old` to `new`, or an empty string if they
+/// are equal.
+pub fn unified(old: &str, new: &str, old_label: &str, new_label: &str) -> String {
+    if old == new {
+        return String::new();
+    }
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(CONTEXT_LINES)
+        .header(old_label, new_label)
+        .to_string()
+}
+
+/// An option whose definition differs between two versions of a module.
+pub struct OptionChange {
+    pub path: String,
+    pub old: Option<String>,
+    pub new: Option<String>,
+}
+
+/// Compares the leaf bindings of two versions of a module. Files that do
+/// not parse contribute no bindings.
+pub fn changed_options(old: &str, new: &str) -> Vec<OptionChange> {
+    let old: BTreeMap<String, String> = editor::bindings(old)
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+    let new: BTreeMap<String, String> = editor::bindings(new)
+        .unwrap_or_default()
+        .into_iter()
+        .collect();
+
+    let mut paths: Vec<&String> = old.keys().chain(new.keys()).collect();
+    paths.sort();
+    paths.dedup();
+    paths
+        .into_iter()
+        .filter(|path| old.get(*path) != new.get(*path))
+        .map(|path| OptionChange {
+            path: path.clone(),
+            old: old.get(path).cloned(),
+            new: new.get(path).cloned(),
+        })
+        .collect()
+}
@@ -0,0 +1,1240 @@
+//! Syntax-aware editing of NixOS modules.
+//!
+//! Edits are located using the rnix syntax tree and applied as plain text
+//! splices, so everything outside the touched bindings (comments, blank lines,
+//! formatting) is preserved byte for byte.
+
+use rnix::ast::{self, HasEntry};
+use rnix::{Root, SyntaxKind, SyntaxToken, TextRange};
+use rowan::ast::AstNode;
+use std::fmt;
+
+pub const SYSTEM_PACKAGES: &str = "environment.systemPackages";
+
+#[derive(Debug)]
+pub enum EditError {
+    /// The file is not valid Nix
+    Parse(String),
+    /// The file does not evaluate to a module attribute set
+    NoModule,
+    /// The binding exists but has a shape we do not know how to edit
+    Unsupported(String),
+    /// A value given on the command line is not a usable Nix expression
+    InvalidValue(String),
+}
+
+impl fmt::Display for EditError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            EditError::Parse(msg) => write!(f, "failed to parse Nix file: {}", msg),
+            EditError::NoModule => write!(f, "could not find the module attribute set"),
+            EditError::Unsupported(msg) => write!(f, "{}", msg),
+            EditError::InvalidValue(msg) => write!(f, "invalid value: {}", msg),
+        }
+    }
+}
+
+impl std::error::Error for EditError {}
+
+impl From<EditError> for std::io::Error {
+    fn from(err: EditError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidData, err)
+    }
+}
+
+pub fn parse(content: &str) -> Result<Root, EditError> {
+    let parse = Root::parse(content);
+    if let Some(err) = parse.errors().first() {
+        return Err(EditError::Parse(err.to_string()));
+    }
+    Ok(parse.tree())
+}
+
+/// Names a value may refer to without binding them itself: module arguments
+/// and the builtins that are in scope everywhere. Every builtin is also in
+/// scope with a `__` prefix, e.g. `__attrNames`.
+const SCOPE_NAMES: &[&str] = &[
+    "abort",
+    "baseNameOf",
+    "break",
+    "builtins",
+    "config",
+    "derivation",
+    "dirOf",
+    "false",
+    "fetchGit",
+    "fetchMercurial",
+    "fetchTarball",
+    "fetchTree",
+    "fromTOML",
+    "import",
+    "isNull",
+    "lib",
+    "map",
+    "modulesPath",
+    "null",
+    "options",
+    "pkgs",
+    "placeholder",
+    "removeAttrs",
+    "scopedImport",
+    "throw",
+    "toString",
+    "true",
+];
+
+/// Checks that `value` is a Nix expression that can be written as the value
+/// of a binding: it must parse on its own and must not refer to variables
+/// that are not in scope in a module, which usually means an unquoted string.
+pub fn check_value(value: &str) -> Result<(), EditError> {
+    let parse = Root::parse(value);
+    if let Some(err) = parse.errors().first() {
+        return Err(EditError::InvalidValue(format!(
+            "'{}' is not a valid Nix expression: {}",
+            value, err
+        )));
+    }
+    let root = parse.tree();
+    if root.expr().is_none() {
+        return Err(EditError::InvalidValue("the value is empty".to_string()));
+    }
+
+    // `with` brings names into scope that we cannot know about
+    let nodes: Vec<_> = root.syntax().descendants().collect();
+    if nodes
+        .iter()
+        .any(|node| node.kind() == SyntaxKind::NODE_WITH)
+    {
+        return Ok(());
+    }
+
+    let mut bound = Vec::new();
+    let mut references = Vec::new();
+    for node in nodes
+        .iter()
+        .filter(|node| node.kind() == SyntaxKind::NODE_IDENT)
+    {
+        let name = node.text().to_string();
+        match node.parent().map(|parent| parent.kind()) {
+            Some(SyntaxKind::NODE_ATTRPATH) | Some(SyntaxKind::NODE_INHERIT) => bound.push(name),
+            Some(SyntaxKind::NODE_IDENT_PARAM)
+            | Some(SyntaxKind::NODE_PAT_ENTRY)
+            | Some(SyntaxKind::NODE_PAT_BIND) => bound.push(name),
+            _ => references.push(name),
+        }
+    }
+
+    match references.into_iter().find(|name| {
+        !SCOPE_NAMES.contains(&name.as_str()) && !name.starts_with("__") && !bound.contains(name)
+    }) {
+        Some(name) if value.trim() == name => Err(EditError::InvalidValue(format!(
+            "'{}' is not a Nix value; quote strings, e.g. '\"{}\"'",
+            value, name
+        ))),
+        Some(name) => Err(EditError::InvalidValue(format!(
+            "'{}' refers to undefined variable '{}'",
+            value, name
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Finds the attribute set a module evaluates to, looking through the
+/// argument pattern, `let ... in`, `with`, `assert` and parentheses.
+pub fn module_attrset(root: &Root) -> Option<ast::AttrSet> {
+    let mut expr = root.expr()?;
+    loop {
+        expr = match expr {
+            ast::Expr::AttrSet(set) => return Some(set),
+            ast::Expr::Lambda(lambda) => lambda.body()?,
+            ast::Expr::LetIn(let_in) => let_in.body()?,
+            ast::Expr::With(with) => with.body()?,
+            ast::Expr::Assert(assert) => assert.body()?,
+            ast::Expr::Paren(paren) => paren.expr()?,
+            _ => return None,
+        };
+    }
+}
+
+/// What an edit did to the file.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Added,
+    Updated,
+    Removed,
+    Unchanged,
+}
+
+/// Where a binding for an option path lives in a module.
+enum Location {
+    /// An existing binding for exactly this path
+    Binding(ast::AttrpathValue),
+    /// The innermost attribute set covering a prefix of the path, and the
+    /// remainder of the path relative to it
+    Parent(ast::AttrSet, Vec<String>),
+    /// A prefix of the path, relative to the searched set, bound to something
+    /// other than an attribute set literal (e.g. `import ./fw.nix`), which
+    /// nx cannot look into
+    Opaque(Vec<String>),
+}
+
+impl Location {
+    fn opaque_error(path: &[String]) -> EditError {
+        EditError::Unsupported(format!(
+            "{} is set to an expression nx cannot look into, such as an import; edit it by hand",
+            join_path(path)
+        ))
+    }
+}
+
+/// Adds a package to `environment.systemPackages`, creating the list if needed.
+pub fn add_package(content: &str, package: &str) -> Result<(String, Change), EditError> {
+    add_element(
+        content,
+        SYSTEM_PACKAGES,
+        |item| is_package(item, package),
+        |with_pkgs| {
+            if with_pkgs {
+                package.to_string()
+            } else {
+                format!("pkgs.{}", package)
+            }
+        },
+        format!("with pkgs; [\n  {}\n]", package),
+    )
+}
+
+/// Appends `element` to the list bound at `path`, creating the binding if
+/// needed. Nothing changes when an equal element is already in the list.
+pub fn add_to_list(
+    content: &str,
+    path: &str,
+    element: &str,
+) -> Result<(String, Change), EditError> {
+    let element = list_element(element)?;
+    let (result, change) = add_element(
+        content,
+        path,
+        |item| item.syntax().text() == element.as_str(),
+        |_| element.clone(),
+        format!("[\n  {}\n]", element),
+    )?;
+    if let Some(err) = Root::parse(&result).errors().first() {
+        return Err(EditError::InvalidValue(format!(
+            "adding {} to {} would not leave valid Nix: {}",
+            element, path, err
+        )));
+    }
+    Ok((result, change))
+}
+
+/// Returns `element` as it has to be written inside a list. List items are
+/// separated by whitespace only, so anything but a simple value (a negative
+/// number, a function call, a `with`, `let` or `if` expression, ...) is
+/// parenthesized.
+pub fn list_element(element: &str) -> Result<String, EditError> {
+    let element = element.trim();
+    let parse = Root::parse(element);
+    if let Some(err) = parse.errors().first() {
+        return Err(EditError::InvalidValue(format!(
+            "'{}' is not a valid Nix expression: {}",
+            element, err
+        )));
+    }
+    let expr = parse
+        .tree()
+        .expr()
+        .ok_or_else(|| EditError::InvalidValue("the value is empty".to_string()))?;
+    let simple = matches!(
+        expr,
+        ast::Expr::Ident(_)
+            | ast::Expr::Literal(_)
+            | ast::Expr::Str(_)
+            | ast::Expr::Path(_)
+            | ast::Expr::Paren(_)
+            | ast::Expr::List(_)
+            | ast::Expr::AttrSet(_)
+            | ast::Expr::Select(_)
+    );
+    Ok(if simple {
+        element.to_string()
+    } else {
+        format!("({})", element)
+    })
+}
+
+/// Appends the item built by `item` (given whether the list is in
+/// `with pkgs;`) to the list at `path`, unless an element satisfies
+/// `present`. Missing bindings are created as `path = new_list;`.
+fn add_element<P, I>(
+    content: &str,
+    path: &str,
+    present: P,
+    item: I,
+    new_list: String,
+) -> Result<(String, Change), EditError>
+where
+    P: Fn(&ast::Expr) -> bool,
+    I: FnOnce(bool) -> String,
+{
+    let root = parse(content)?;
+    let module = module_attrset(&root).ok_or(EditError::NoModule)?;
+    let target = split_path(path);
+
+    match locate(&module, &target) {
+        Location::Binding(binding) => {
+            let value = binding.value().ok_or(EditError::NoModule)?;
+            if list_items(value.clone()).iter().any(present) {
+                return Ok((content.to_string(), Change::Unchanged));
+            }
+
+            let (list, with_pkgs) = package_list(value, false)
+                .ok_or_else(|| EditError::Unsupported(format!("{} is not a list literal", path)))?;
+            let first = list.items().next().map(|item| item.syntax().text_range());
+            let close = list.r_brack_token().ok_or(EditError::NoModule)?;
+            Ok((
+                insert_line(content, first, &close, &item(with_pkgs)),
+                Change::Added,
+            ))
+        }
+        Location::Parent(..) if has_bindings_below(&module, &target) => Err(
+            EditError::Unsupported(format!("{} is an attribute set, not a list", path)),
+        ),
+        Location::Opaque(prefix) => Err(Location::opaque_error(&prefix)),
+        Location::Parent(set, rest) => {
+            let entry = format!("{} = {};", join_path(&rest), new_list);
+            Ok((insert_entry(content, &set, &entry)?, Change::Added))
+        }
+    }
+}
+
+/// Sets `path = value;` in the module, replacing an existing binding's value.
+///
+/// Existing bindings are found through nested attribute sets, so
+/// `networking = { hostName = ...; };` is updated rather than duplicated.
+/// Where bindings below `path` exist instead, as in
+/// `services.openssh.enable = true;` for `services.openssh`, an attribute set
+/// value is merged into them.
+pub fn set_option(content: &str, path: &str, value: &str) -> Result<(String, Change), EditError> {
+    let root = parse(content)?;
+    let module = module_attrset(&root).ok_or(EditError::NoModule)?;
+    let target = split_path(path);
+
+    match locate(&module, &target) {
+        Location::Binding(binding) => {
+            let current = binding.value().ok_or(EditError::NoModule)?;
+            if current.syntax().text() == value.trim() {
+                return Ok((content.to_string(), Change::Unchanged));
+            }
+            // Continuation lines of multi-line values follow the binding's indent
+            let start = usize::from(binding.syntax().text_range().start());
+            let indent = current_line_indent(content, start);
+            let value = value.trim().replace('\n', &format!("\n{}", indent));
+            let range = current.syntax().text_range();
+            Ok((replace_range(content, range, &value), Change::Updated))
+        }
+        Location::Parent(..) if has_bindings_below(&module, &target) => {
+            merge_into(content, &target, value)
+        }
+        Location::Opaque(prefix) => Err(Location::opaque_error(&prefix)),
+        Location::Parent(set, rest) => {
+            let entry = format!("{} = {};", join_path(&rest), value);
+            Ok((insert_entry(content, &set, &entry)?, Change::Added))
+        }
+    }
+}
+
+/// Sets each binding of the attribute set `value` below `path` on its own.
+fn merge_into(content: &str, path: &[String], value: &str) -> Result<(String, Change), EditError> {
+    let unsupported = || {
+        EditError::Unsupported(format!(
+            "{} is already defined by bindings below it; set those individually or unset them first",
+            join_path(path)
+        ))
+    };
+    let parse = Root::parse(value);
+    let set = match parse.tree().expr() {
+        Some(ast::Expr::AttrSet(set))
+            if parse.errors().is_empty()
+                && set.rec_token().is_none()
+                && set.inherits().next().is_none() =>
+        {
+            set
+        }
+        _ => return Err(unsupported()),
+    };
+
+    let mut content = content.to_string();
+    let mut result = Change::Unchanged;
+    for binding in set.attrpath_values() {
+        let (Some(names), Some(value)) = (
+            binding.attrpath().and_then(|p| attrpath_names(&p)),
+            binding.value(),
+        ) else {
+            return Err(unsupported());
+        };
+        let (updated, change) = set_option(
+            &content,
+            &join_path(&[path, &names].concat()),
+            &value.syntax().text().to_string(),
+        )?;
+        content = updated;
+        if change != Change::Unchanged {
+            result = Change::Updated;
+        }
+    }
+    Ok((content, result))
+}
+
+/// Whether the module binds anything strictly below `path`.
+fn has_bindings_below(module: &ast::AttrSet, path: &[String]) -> bool {
+    let mut leaves = Vec::new();
+    collect_bindings(module, &[], &mut leaves);
+    leaves.iter().any(|(binding, _)| {
+        let names = split_path(binding);
+        names.len() > path.len() && names.starts_with(path)
+    })
+}
+
+/// Removes a package from every list in `environment.systemPackages`.
+pub fn remove_package(content: &str, package: &str) -> Result<(String, Change), EditError> {
+    remove_elements(content, SYSTEM_PACKAGES, |item| is_package(item, package))
+}
+
+/// Removes every occurrence of `element` from the list bound at `path`.
+pub fn remove_from_list(
+    content: &str,
+    path: &str,
+    element: &str,
+) -> Result<(String, Change), EditError> {
+    let element = element.trim();
+    let written = list_element(element)?;
+    remove_elements(content, path, |item| {
+        let text = item.syntax().text();
+        text == element || text == written.as_str()
+    })
+}
+
+fn remove_elements<F>(content: &str, path: &str, matches: F) -> Result<(String, Change), EditError>
+where
+    F: Fn(&ast::Expr) -> bool,
+{
+    let root = parse(content)?;
+    let module = module_attrset(&root).ok_or(EditError::NoModule)?;
+
+    let Location::Binding(binding) = locate(&module, &split_path(path)) else {
+        return Ok((content.to_string(), Change::Unchanged));
+    };
+    let value = binding.value().ok_or(EditError::NoModule)?;
+    let ranges: Vec<TextRange> = list_items(value)
+        .iter()
+        .filter(|item| matches(item))
+        .map(|item| item.syntax().text_range())
+        .collect();
+    if ranges.is_empty() {
+        return Ok((content.to_string(), Change::Unchanged));
+    }
+
+    // Remove back to front so earlier ranges stay valid
+    let mut result = content.to_string();
+    for range in ranges.into_iter().rev() {
+        result = remove_range(&result, range);
+    }
+    Ok((result, Change::Removed))
+}
+
+/// Removes the binding for `path` from the module.
+pub fn remove_option(content: &str, path: &str) -> Result<(String, Change), EditError> {
+    let root = parse(content)?;
+    let module = module_attrset(&root).ok_or(EditError::NoModule)?;
+
+    match locate(&module, &split_path(path)) {
+        Location::Binding(binding) => Ok((
+            remove_range(content, binding.syntax().text_range()),
+            Change::Removed,
+        )),
+        Location::Parent(..) | Location::Opaque(_) => Ok((content.to_string(), Change::Unchanged)),
+    }
+}
+
+/// Sets `path` to `value` only if a binding for it already exists.
+pub fn update_option(
+    content: &str,
+    path: &str,
+    value: &str,
+) -> Result<(String, Change), EditError> {
+    let root = parse(content)?;
+    let module = module_attrset(&root).ok_or(EditError::NoModule)?;
+
+    match locate(&module, &split_path(path)) {
+        Location::Binding(_) => set_option(content, path, value),
+        Location::Parent(..) | Location::Opaque(_) => Ok((content.to_string(), Change::Unchanged)),
+    }
+}
+
+/// Adds `path` to the module's `imports` list, creating the list if needed.
+pub fn add_import(content: &str, path: &str) -> Result<(String, Change), EditError> {
+    let root = parse(content)?;
+    let module = module_attrset(&root).ok_or(EditError::NoModule)?;
+
+    match locate(&module, &split_path("imports")) {
+        Location::Binding(binding) => {
+            let value = binding.value().ok_or(EditError::NoModule)?;
+            if list_items(value.clone())
+                .iter()
+                .any(|item| item.syntax().text() == path)
+            {
+                return Ok((content.to_string(), Change::Unchanged));
+            }
+            let ast::Expr::List(list) = value else {
+                return Err(EditError::Unsupported(
+                    "imports is not a list literal".to_string(),
+                ));
+            };
+            let first = list.items().next().map(|item| item.syntax().text_range());
+            let close = list.r_brack_token().ok_or(EditError::NoModule)?;
+            Ok((insert_line(content, first, &close, path), Change::Added))
+        }
+        Location::Parent(set, _) => {
+            let entry = format!("imports = [ {} ];", path);
+            Ok((insert_entry(content, &set, &entry)?, Change::Added))
+        }
+        Location::Opaque(prefix) => Err(Location::opaque_error(&prefix)),
+    }
+}
+
+/// Returns the local paths listed in the module's `imports`.
+pub fn import_paths(content: &str) -> Result<Vec<String>, EditError> {
+    let root = parse(content)?;
+    let module = module_attrset(&root).ok_or(EditError::NoModule)?;
+
+    let Location::Binding(binding) = locate(&module, &split_path("imports")) else {
+        return Ok(Vec::new());
+    };
+    Ok(binding
+        .value()
+        .map(list_items)
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|item| matches!(item, ast::Expr::Path(_)))
+        .map(|item| item.syntax().text().to_string())
+        .filter(|path| !path.starts_with('<'))
+        .collect())
+}
+
+/// Returns how closely the module defines `path`: its full length if `path`
+/// itself or a value below it is bound, the length of the deepest attribute
+/// set bound above it otherwise, and 0 if the module defines neither.
+pub fn definition_depth(content: &str, path: &str) -> Result<usize, EditError> {
+    let root = parse(content)?;
+    let module = module_attrset(&root).ok_or(EditError::NoModule)?;
+    Ok(set_definition_depth(&module, &[], &split_path(path)))
+}
+
+fn set_definition_depth(set: &ast::AttrSet, prefix: &[String], target: &[String]) -> usize {
+    let mut depth = 0;
+    for binding in set.attrpath_values() {
+        let Some(names) = binding.attrpath().and_then(|p| attrpath_names(&p)) else {
+            continue;
+        };
+        let path = [prefix, &names].concat();
+        let shared = path.iter().zip(target).take_while(|(a, b)| a == b).count();
+        if shared == target.len() {
+            return target.len();
+        }
+        if shared < path.len() {
+            continue;
+        }
+        if let Some(ast::Expr::AttrSet(inner)) = binding.value() {
+            depth = depth
+                .max(path.len())
+                .max(set_definition_depth(&inner, &path, target));
+        }
+    }
+    depth
+}
+
+/// Lists every leaf binding of the module as `(path, value)`, descending
+/// into nested attribute sets.
+pub fn bindings(content: &str) -> Result<Vec<(String, String)>, EditError> {
+    let root = parse(content)?;
+    let module = module_attrset(&root).ok_or(EditError::NoModule)?;
+    let mut result = Vec::new();
+    collect_bindings(&module, &[], &mut result);
+    Ok(result)
+}
+
+fn collect_bindings(set: &ast::AttrSet, prefix: &[String], result: &mut Vec<(String, String)>) {
+    for binding in set.attrpath_values() {
+        let (Some(names), Some(value)) = (
+            binding.attrpath().and_then(|p| attrpath_names(&p)),
+            binding.value(),
+        ) else {
+            continue;
+        };
+        let path = [prefix, &names].concat();
+        match value {
+            ast::Expr::AttrSet(inner) if inner.rec_token().is_none() => {
+                collect_bindings(&inner, &path, result)
+            }
+            value => result.push((join_path(&path), value.syntax().text().to_string())),
+        }
+    }
+}
+
+/// Returns the elements of a list expression (looking through `with` and
+/// parentheses) as source text.
+pub fn list_elements(value: &str) -> Vec<String> {
+    let parse = Root::parse(value);
+    parse
+        .tree()
+        .expr()
+        .map(list_items)
+        .unwrap_or_default()
+        .iter()
+        .map(|item| item.syntax().text().to_string())
+        .collect()
+}
+
+/// Strips a `lib.mkForce` wrapper added by the temporary overlay.
+pub fn strip_force(value: &str) -> String {
+    let parse = Root::parse(value);
+    let Some(ast::Expr::Apply(apply)) = parse.tree().expr() else {
+        return value.to_string();
+    };
+    if !apply
+        .lambda()
+        .is_some_and(|f| f.syntax().text() == "lib.mkForce")
+    {
+        return value.to_string();
+    }
+    match apply.argument() {
+        Some(ast::Expr::Paren(paren)) => paren
+            .expr()
+            .map_or_else(|| value.to_string(), |e| e.syntax().text().to_string()),
+        Some(arg) => arg.syntax().text().to_string(),
+        None => value.to_string(),
+    }
+}
+
+/// Whether a value expression merges with other definitions (lists and
+/// attribute sets) instead of conflicting with them.
+pub fn is_mergeable(value: &str) -> bool {
+    let parse = Root::parse(value);
+    let mut expr = parse.tree().expr();
+    while let Some(ast::Expr::Paren(paren)) = expr {
+        expr = paren.expr();
+    }
+    matches!(expr, Some(ast::Expr::List(_)) | Some(ast::Expr::AttrSet(_)))
+}
+
+/// Returns the 1-based line and value text of the binding for `path`.
+pub fn find_option(content: &str, path: &str) -> Result<Option<(usize, String)>, EditError> {
+    let root = parse(content)?;
+    let module = module_attrset(&root).ok_or(EditError::NoModule)?;
+
+    match locate(&module, &split_path(path)) {
+        Location::Binding(binding) => {
+            let value = binding.value().ok_or(EditError::NoModule)?;
+            let offset = usize::from(binding.syntax().text_range().start());
+            let line = content[..offset].matches('\n').count() + 1;
+            Ok(Some((line, value.syntax().text().to_string())))
+        }
+        Location::Parent(..) | Location::Opaque(_) => Ok(None),
+    }
+}
+
+/// Splits an option path into attribute names. Names containing dots can be
+/// written in double quotes, as in Nix: `boot.kernel.sysctl."net.ipv4.ip_forward"`.
+pub fn split_path(path: &str) -> Vec<String> {
+    let mut names = Vec::new();
+    let mut name = String::new();
+    let mut quoted = false;
+    let mut chars = path.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => quoted = !quoted,
+            '\\' if quoted => name.extend(chars.next()),
+            '.' if !quoted => names.push(std::mem::take(&mut name)),
+            c => name.push(c),
+        }
+    }
+    names.push(name);
+    names
+}
+
+/// Joins attribute names into an option path, quoting names that are not
+/// plain identifiers.
+pub fn join_path<S: AsRef<str>>(names: &[S]) -> String {
+    names
+        .iter()
+        .map(|name| attr_name(name.as_ref()))
+        .collect::<Vec<_>>()
+        .join(".")
+}
+
+/// Renders an attribute name, quoting it unless it is a plain identifier.
+pub fn attr_name(name: &str) -> String {
+    let mut chars = name.chars();
+    let is_ident = chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '\''))
+        && !KEYWORDS.contains(&name);
+    if is_ident {
+        name.to_string()
+    } else {
+        quote(name)
+    }
+}
+
+const KEYWORDS: &[&str] = &[
+    "assert", "else", "if", "in", "inherit", "let", "rec", "then", "with",
+];
+
+/// Renders `text` as a double-quoted Nix string.
+pub fn quote(text: &str) -> String {
+    let escaped = text
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace("${", "\\${")
+        .replace('\n', "\\n");
+    format!("\"{}\"", escaped)
+}
+
+/// Returns the static names of an attribute path, or `None` if any
+/// component is interpolated.
+pub fn attrpath_names(attrpath: &ast::Attrpath) -> Option<Vec<String>> {
+    attrpath
+        .attrs()
+        .map(|attr| match attr {
+            ast::Attr::Ident(ident) => Some(ident.syntax().text().to_string()),
+            ast::Attr::Str(string) => {
+                let parts = string.normalized_parts();
+                match parts.as_slice() {
+                    [] => Some(String::new()),
+                    [ast::InterpolPart::Literal(text)] => Some(text.clone()),
+                    _ => None,
+                }
+            }
+            ast::Attr::Dynamic(_) => None,
+        })
+        .collect()
+}
+
+fn locate(set: &ast::AttrSet, path: &[String]) -> Location {
+    let mut parent = None;
+    for binding in set.attrpath_values() {
+        let Some(names) = binding.attrpath().and_then(|p| attrpath_names(&p)) else {
+            continue;
+        };
+        if names == path {
+            return Location::Binding(binding);
+        }
+        if names.len() < path.len() && path.starts_with(&names) {
+            match binding.value() {
+                Some(ast::Expr::AttrSet(inner)) => match locate(&inner, &path[names.len()..]) {
+                    found @ Location::Binding(_) => return found,
+                    Location::Opaque(rest) => return Location::Opaque([names, rest].concat()),
+                    nested => parent = parent.or(Some(nested)),
+                },
+                _ => return Location::Opaque(names),
+            }
+        }
+    }
+    parent.unwrap_or_else(|| Location::Parent(set.clone(), path.to_vec()))
+}
+
+/// Collects the elements of every list literal in a list expression.
+fn list_items(expr: ast::Expr) -> Vec<ast::Expr> {
+    match expr {
+        ast::Expr::List(list) => list.items().collect(),
+        ast::Expr::Paren(paren) => paren.expr().map(list_items).unwrap_or_default(),
+        ast::Expr::With(with) => with.body().map(list_items).unwrap_or_default(),
+        ast::Expr::BinOp(op) => {
+            let mut items = op.lhs().map(list_items).unwrap_or_default();
+            items.extend(op.rhs().map(list_items).unwrap_or_default());
+            items
+        }
+        _ => Vec::new(),
+    }
+}
+
+fn is_package(item: &ast::Expr, package: &str) -> bool {
+    let text = item.syntax().text().to_string();
+    text == package || text.strip_prefix("pkgs.") == Some(package)
+}
+
+/// Finds the list literal to append to, and whether it is in `with pkgs;`.
+fn package_list(expr: ast::Expr, with_pkgs: bool) -> Option<(ast::List, bool)> {
+    match expr {
+        ast::Expr::List(list) => Some((list, with_pkgs)),
+        ast::Expr::Paren(paren) => package_list(paren.expr()?, with_pkgs),
+        ast::Expr::With(with) => {
+            let is_pkgs = with
+                .namespace()
+                .is_some_and(|ns| ns.syntax().text() == "pkgs");
+            package_list(with.body()?, with_pkgs || is_pkgs)
+        }
+        ast::Expr::BinOp(op) if op.operator() == Some(ast::BinOpKind::Concat) => op
+            .lhs()
+            .and_then(|lhs| package_list(lhs, with_pkgs))
+            .or_else(|| op.rhs().and_then(|rhs| package_list(rhs, with_pkgs))),
+        _ => None,
+    }
+}
+
+fn insert_entry(content: &str, set: &ast::AttrSet, entry: &str) -> Result<String, EditError> {
+    let first = set
+        .entries()
+        .next()
+        .map(|entry| entry.syntax().text_range());
+    let close = set.r_curly_token().ok_or(EditError::NoModule)?;
+    Ok(insert_line(content, first, &close, entry))
+}
+
+/// Inserts `text` as the last element of a bracketed block closed by `close`.
+///
+/// Multi-line blocks get a new line indented like the first existing element;
+/// single-line blocks get the text appended inline.
+fn insert_line(content: &str, first: Option<TextRange>, close: &SyntaxToken, text: &str) -> String {
+    let close_pos = usize::from(close.text_range().start());
+    let close_indent = line_indent(content, close_pos);
+    let indent = first
+        .and_then(|range| line_indent(content, usize::from(range.start())))
+        .unwrap_or_else(|| format!("{}  ", close_indent.as_deref().unwrap_or("")));
+
+    if let Some(close_indent) = close_indent {
+        let line_start = close_pos - close_indent.len();
+        let block = indent_lines(text, &indent);
+        return format!(
+            "{}{}\n{}",
+            &content[..line_start],
+            block,
+            &content[line_start..]
+        );
+    }
+
+    let before = content[..close_pos].trim_end();
+    if text.contains('\n') {
+        let outer = current_line_indent(content, close_pos);
+        format!(
+            "{}\n{}\n{}{}",
+            before,
+            indent_lines(text, &format!("{}  ", outer)),
+            outer,
+            &content[close_pos..]
+        )
+    } else {
+        format!("{} {} {}", before, text, &content[close_pos..])
+    }
+}
+
+fn indent_lines(text: &str, indent: &str) -> String {
+    text.lines()
+        .map(|line| format!("{}{}", indent, line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Returns the indentation before `pos` if only whitespace precedes it on its line.
+fn line_indent(content: &str, pos: usize) -> Option<String> {
+    let line_start = content[..pos].rfind('\n').map_or(0, |i| i + 1);
+    let prefix = &content[line_start..pos];
+    if prefix.chars().all(|c| c == ' ' || c == '\t') {
+        Some(prefix.to_string())
+    } else {
+        None
+    }
+}
+
+fn current_line_indent(content: &str, pos: usize) -> String {
+    let line_start = content[..pos].rfind('\n').map_or(0, |i| i + 1);
+    content[line_start..pos]
+        .chars()
+        .take_while(|c| *c == ' ' || *c == '\t')
+        .collect()
+}
+
+fn replace_range(content: &str, range: TextRange, text: &str) -> String {
+    format!(
+        "{}{}{}",
+        &content[..usize::from(range.start())],
+        text,
+        &content[usize::from(range.end())..]
+    )
+}
+
+/// Removes the text in `range`, taking the whole line with it when nothing
+/// but whitespace or a trailing comment would be left behind.
+fn remove_range(content: &str, range: TextRange) -> String {
+    let start = usize::from(range.start());
+    let end = usize::from(range.end());
+    let line_start = content[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = content[end..].find('\n').map_or(content.len(), |i| end + i);
+    let before = &content[line_start..start];
+    let after = content[end..line_end].trim_start();
+
+    if before.trim().is_empty() && (after.is_empty() || after.starts_with('#')) {
+        let next_line = (line_end + 1).min(content.len());
+        return format!("{}{}", &content[..line_start], &content[next_line..]);
+    }
+
+    let trailing = content[end..line_end].len() - content[end..line_end].trim_start().len();
+    format!("{}{}", &content[..start], &content[end + trailing..])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_parses(content: &str) {
+        parse(content).unwrap_or_else(|e| panic!("{}\n{}", e, content));
+    }
+
+    #[test]
+    fn set_option_updates_dotted_binding() {
+        let content = "{ ... }:\n{\n  networking.hostName = \"a\";\n}\n";
+        let (result, change) = set_option(content, "networking.hostName", "\"b\"").unwrap();
+        assert_eq!(change, Change::Updated);
+        assert_eq!(result, "{ ... }:\n{\n  networking.hostName = \"b\";\n}\n");
+    }
+
+    #[test]
+    fn set_option_updates_nested_binding() {
+        let content = "{ ... }:\n{\n  networking = {\n    hostName = \"a\";\n  };\n}\n";
+        let (result, change) = set_option(content, "networking.hostName", "\"b\"").unwrap();
+        assert_eq!(change, Change::Updated);
+        assert_eq!(
+            result,
+            "{ ... }:\n{\n  networking = {\n    hostName = \"b\";\n  };\n}\n"
+        );
+    }
+
+    #[test]
+    fn set_option_adds_to_nested_set() {
+        let content = "{ ... }:\n{\n  networking = {\n    hostName = \"a\";\n  };\n}\n";
+        let (result, change) = set_option(content, "networking.domain", "\"lan\"").unwrap();
+        assert_eq!(change, Change::Added);
+        assert_eq!(
+            result,
+            "{ ... }:\n{\n  networking = {\n    hostName = \"a\";\n    domain = \"lan\";\n  };\n}\n"
+        );
+    }
+
+    #[test]
+    fn set_option_reports_unchanged_value() {
+        let content = "{ ... }:\n{\n  networking = { hostName = \"a\"; };\n}\n";
+        let (result, change) = set_option(content, "networking.hostName", "\"a\"").unwrap();
+        assert_eq!(change, Change::Unchanged);
+        assert_eq!(result, content);
+    }
+
+    #[test]
+    fn set_option_merges_into_dotted_bindings_below() {
+        let content = "{ ... }:\n{\n  services.openssh.enable = true;\n}\n";
+        let (result, change) = set_option(content, "services.openssh", "{ }").unwrap();
+        assert_eq!(change, Change::Unchanged);
+        assert_eq!(result, content);
+
+        let (result, change) = set_option(
+            content,
+            "services.openssh",
+            "{ enable = false; ports = [ 2222 ]; }",
+        )
+        .unwrap();
+        assert_eq!(change, Change::Updated);
+        assert_eq!(
+            result,
+            "{ ... }:\n{\n  services.openssh.enable = false;\n  services.openssh.ports = [ 2222 ];\n}\n"
+        );
+        assert_parses(&result);
+    }
+
+    #[test]
+    fn set_option_merges_into_nested_bindings_below() {
+        let content = "{ ... }:\n{\n  services = {\n    openssh.enable = true;\n  };\n}\n";
+        let (result, _) = set_option(content, "services.openssh", "{ enable = false; }").unwrap();
+        assert_eq!(
+            result,
+            "{ ... }:\n{\n  services = {\n    openssh.enable = false;\n  };\n}\n"
+        );
+    }
+
+    #[test]
+    fn set_option_refuses_scalar_over_bindings_below() {
+        let content = "{ ... }:\n{\n  services.openssh.enable = true;\n}\n";
+        assert!(matches!(
+            set_option(content, "services.openssh", "null"),
+            Err(EditError::Unsupported(_))
+        ));
+    }
+
+    #[test]
+    fn add_package_is_idempotent() {
+        let content = "{ pkgs, ... }:\n{\n  environment.systemPackages = with pkgs; [ git ];\n}\n";
+        let (result, change) = add_package(content, "git").unwrap();
+        assert_eq!(change, Change::Unchanged);
+        assert_eq!(result, content);
+    }
+
+    #[test]
+    fn comments_with_braces_are_ignored() {
+        let content =
+            "{ pkgs, ... }:\n{\n  # { not = a set; }\n  networking.hostName = \"a\"; # }\n}\n";
+        let (result, change) = set_option(content, "time.timeZone", "\"UTC\"").unwrap();
+        assert_eq!(change, Change::Added);
+        assert_eq!(
+            result,
+            "{ pkgs, ... }:\n{\n  # { not = a set; }\n  networking.hostName = \"a\"; # }\n  time.timeZone = \"UTC\";\n}\n"
+        );
+    }
+
+    #[test]
+    fn let_in_body_is_edited() {
+        let content = "{ pkgs, ... }:\nlet\n  user = \"alice\";\nin\n{\n  users.users.${user}.isNormalUser = true;\n}\n";
+        let (result, change) = add_package(content, "git").unwrap();
+        assert_eq!(change, Change::Added);
+        assert_eq!(
+            result,
+            "{ pkgs, ... }:\nlet\n  user = \"alice\";\nin\n{\n  users.users.${user}.isNormalUser = true;\n  environment.systemPackages = with pkgs; [\n    git\n  ];\n}\n"
+        );
+    }
+
+    #[test]
+    fn package_list_without_with_pkgs_gets_prefixed_names() {
+        let content =
+            "{ pkgs, ... }:\n{\n  environment.systemPackages = [\n    pkgs.git\n  ];\n}\n";
+        let (result, change) = add_package(content, "htop").unwrap();
+        assert_eq!(change, Change::Added);
+        assert_eq!(
+            result,
+            "{ pkgs, ... }:\n{\n  environment.systemPackages = [\n    pkgs.git\n    pkgs.htop\n  ];\n}\n"
+        );
+
+        let (unchanged, change) = add_package(&result, "git").unwrap();
+        assert_eq!(change, Change::Unchanged);
+        assert_eq!(unchanged, result);
+
+        let (removed, change) = remove_package(&result, "git").unwrap();
+        assert_eq!(change, Change::Removed);
+        assert_eq!(
+            removed,
+            "{ pkgs, ... }:\n{\n  environment.systemPackages = [\n    pkgs.htop\n  ];\n}\n"
+        );
+    }
+
+    #[test]
+    fn inline_list_stays_inline() {
+        let content =
+            "{ pkgs, ... }:\n{\n  environment.systemPackages = with pkgs; [ git vim ];\n}\n";
+        let (result, _) = add_package(content, "htop").unwrap();
+        assert_eq!(
+            result,
+            "{ pkgs, ... }:\n{\n  environment.systemPackages = with pkgs; [ git vim htop ];\n}\n"
+        );
+
+        let (removed, change) = remove_package(&result, "vim").unwrap();
+        assert_eq!(change, Change::Removed);
+        assert_eq!(
+            removed,
+            "{ pkgs, ... }:\n{\n  environment.systemPackages = with pkgs; [ git htop ];\n}\n"
+        );
+    }
+
+    #[test]
+    fn multi_line_list_gets_a_new_line() {
+        let content = "{ pkgs, ... }:\n{\n  environment.systemPackages = with pkgs; [\n    git\n    vim\n  ];\n}\n";
+        let (result, _) = add_package(content, "htop").unwrap();
+        assert_eq!(
+            result,
+            "{ pkgs, ... }:\n{\n  environment.systemPackages = with pkgs; [\n    git\n    vim\n    htop\n  ];\n}\n"
+        );
+    }
+
+    #[test]
+    fn nested_attrsets_are_found() {
+        let content =
+            "{ ... }:\n{\n  services = {\n    openssh = {\n      enable = true;\n    };\n  };\n}\n";
+        let (result, change) = set_option(content, "services.openssh.ports", "[ 22 ]").unwrap();
+        assert_eq!(change, Change::Added);
+        assert_eq!(
+            result,
+            "{ ... }:\n{\n  services = {\n    openssh = {\n      enable = true;\n      ports = [ 22 ];\n    };\n  };\n}\n"
+        );
+        assert_eq!(
+            find_option(&result, "services.openssh.enable")
+                .unwrap()
+                .map(|(_, v)| v),
+            Some("true".to_string())
+        );
+    }
+
+    #[test]
+    fn removal_keeps_neighbours_and_comments() {
+        let content = "{ ... }:\n{\n  # Networking\n  networking.hostName = \"a\";\n  networking.firewall.enable = false;\n  # Time\n  time.timeZone = \"UTC\";\n}\n";
+        let (result, change) = remove_option(content, "networking.firewall.enable").unwrap();
+        assert_eq!(change, Change::Removed);
+        assert_eq!(
+            result,
+            "{ ... }:\n{\n  # Networking\n  networking.hostName = \"a\";\n  # Time\n  time.timeZone = \"UTC\";\n}\n"
+        );
+
+        let content = "{ pkgs, ... }:\n{\n  environment.systemPackages = with pkgs; [\n    # editors\n    vim\n    git # vcs\n  ];\n}\n";
+        let (result, _) = remove_package(content, "vim").unwrap();
+        assert_eq!(
+            result,
+            "{ pkgs, ... }:\n{\n  environment.systemPackages = with pkgs; [\n    # editors\n    git # vcs\n  ];\n}\n"
+        );
+        assert_parses(&result);
+    }
+
+    #[test]
+    fn add_to_list_appends_and_creates_lists() {
+        let content = "{ ... }:\n{\n  users.users.alice.extraGroups = [ \"wheel\" ];\n}\n";
+        let (result, change) =
+            add_to_list(content, "users.users.alice.extraGroups", "\"docker\"").unwrap();
+        assert_eq!(change, Change::Added);
+        assert_eq!(
+            result,
+            "{ ... }:\n{\n  users.users.alice.extraGroups = [ \"wheel\" \"docker\" ];\n}\n"
+        );
+
+        let (unchanged, change) =
+            add_to_list(&result, "users.users.alice.extraGroups", "\"wheel\"").unwrap();
+        assert_eq!(change, Change::Unchanged);
+        assert_eq!(unchanged, result);
+
+        let (created, change) =
+            add_to_list(content, "networking.firewall.allowedTCPPorts", "80").unwrap();
+        assert_eq!(change, Change::Added);
+        assert!(created.contains("networking.firewall.allowedTCPPorts = [\n    80\n  ];"));
+        assert_parses(&created);
+    }
+
+    #[test]
+    fn add_to_list_parenthesizes_compound_elements() {
+        let content = "{ pkgs, ... }:\n{\n  networking.firewall.allowedTCPPorts = [ 80 ];\n}\n";
+        let (result, _) =
+            add_to_list(content, "networking.firewall.allowedTCPPorts", "-1").unwrap();
+        assert!(result.contains("[ 80 (-1) ];"), "{}", result);
+        assert_parses(&result);
+
+        let (unchanged, change) =
+            add_to_list(&result, "networking.firewall.allowedTCPPorts", "-1").unwrap();
+        assert_eq!(change, Change::Unchanged);
+        assert_eq!(unchanged, result);
+
+        let (result, _) = add_to_list(
+            content,
+            "environment.systemPackages",
+            "pkgs.callPackage ./x.nix {}",
+        )
+        .unwrap();
+        assert!(
+            result.contains("(pkgs.callPackage ./x.nix {})"),
+            "{}",
+            result
+        );
+        let bindings = bindings(&result).unwrap();
+        let (_, value) = bindings
+            .iter()
+            .find(|(path, _)| path == "environment.systemPackages")
+            .unwrap();
+        assert_eq!(list_elements(value).len(), 1);
+
+        for element in ["pkgs.git", "\"a b\"", "./x.nix", "{ a = 1; }", "(x: x)"] {
+            assert_eq!(list_element(element).unwrap(), element);
+        }
+        for element in ["with pkgs; git", "if true then 1 else 2", "let a = 1; in a"] {
+            assert_eq!(list_element(element).unwrap(), format!("({})", element));
+        }
+        assert!(add_to_list(content, "networking.firewall.allowedTCPPorts", "[").is_err());
+    }
+
+    #[test]
+    fn remove_from_list_keeps_other_elements() {
+        let content = "{ ... }:\n{\n  networking.firewall.allowedTCPPorts = [\n    80\n    (-1)\n    443\n  ];\n}\n";
+        let (result, change) =
+            remove_from_list(content, "networking.firewall.allowedTCPPorts", "-1").unwrap();
+        assert_eq!(change, Change::Removed);
+        assert_eq!(
+            result,
+            "{ ... }:\n{\n  networking.firewall.allowedTCPPorts = [\n    80\n    443\n  ];\n}\n"
+        );
+
+        let (unchanged, change) =
+            remove_from_list(&result, "networking.firewall.allowedTCPPorts", "22").unwrap();
+        assert_eq!(change, Change::Unchanged);
+        assert_eq!(unchanged, result);
+    }
+
+    #[test]
+    fn set_option_refuses_prefix_bound_to_an_expression() {
+        for content in [
+            "{ ... }:\n{\n  networking.firewall = import ./fw.nix;\n}\n",
+            "{ lib, ... }:\n{\n  networking = {\n    firewall = lib.mkIf true { allowPing = true; };\n  };\n}\n",
+        ] {
+            match set_option(content, "networking.firewall.enable", "true") {
+                Err(EditError::Unsupported(msg)) => {
+                    assert!(msg.starts_with("networking.firewall "), "{}", msg)
+                }
+                other => panic!("{:?}", other),
+            }
+            assert!(add_to_list(content, "networking.firewall.allowedTCPPorts", "80").is_err());
+            let (unchanged, change) = remove_option(content, "networking.firewall.enable").unwrap();
+            assert_eq!(change, Change::Unchanged);
+            assert_eq!(unchanged, content);
+        }
+    }
+
+    #[test]
+    fn check_value_accepts_expressions_in_scope() {
+        for value in [
+            "\"myhost\"",
+            "42",
+            "[ 1 (-2) ]",
+            "{ a.b = true; }",
+            "pkgs.git",
+            "lib.mkForce false",
+            "fromTOML (builtins.readFile ./x.toml)",
+            "fetchGit { url = \"https://example.org/x.git\"; }",
+            "scopedImport { } ./x.nix",
+            "__attrNames config.users.users",
+            "x: x + 1",
+            "{ a, ... }: a",
+            "let n = 1; in n",
+            "with pkgs; [ git unknownPackage ]",
+            "rec { a = 1; b = a; }",
+        ] {
+            assert!(
+                check_value(value).is_ok(),
+                "{}: {:?}",
+                value,
+                check_value(value)
+            );
+        }
+    }
+
+    #[test]
+    fn check_value_rejects_unusable_values() {
+        match check_value("myhost") {
+            Err(EditError::InvalidValue(msg)) => assert!(msg.contains("quote strings"), "{}", msg),
+            other => panic!("{:?}", other),
+        }
+        match check_value("toString hostname") {
+            Err(EditError::InvalidValue(msg)) => {
+                assert!(msg.contains("undefined variable 'hostname'"), "{}", msg)
+            }
+            other => panic!("{:?}", other),
+        }
+        assert!(matches!(
+            check_value("{ a = ; }"),
+            Err(EditError::InvalidValue(_))
+        ));
+        assert!(matches!(
+            check_value("   "),
+            Err(EditError::InvalidValue(_))
+        ));
+    }
+}
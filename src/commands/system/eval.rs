@@ -0,0 +1,183 @@
+//! Evaluation and building of NixOS configurations through `nix-instantiate`,
+//! `nix-build` and the `nix` command.
+
+use crate::config::store::Config;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// Evaluates `config.<option>` of the configured NixOS system and returns it
+/// as JSON.
+pub fn eval_option(config: &Config, option: &str) -> io::Result<serde_json::Value> {
+    let output = if config.is_flake() {
+        Command::new("nix")
+            .args(["eval", "--json"])
+            .arg(format!(
+                "{}#nixosConfigurations.\"{}\".config.{}",
+                config.nixos_root.display(),
+                config.flake_host(),
+                option
+            ))
+            .output()?
+    } else {
+        Command::new("nix-instantiate")
+            .args(["<nixpkgs/nixos>", "--eval", "--strict", "--json", "-A"])
+            .arg(format!("config.{}", option))
+            .arg("-I")
+            .arg(format!(
+                "nixos-config={}",
+                config.nixos_config_path().display()
+            ))
+            .output()?
+    };
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(stderr.trim().to_string()));
+    }
+
+    serde_json::from_slice(&output.stdout).map_err(io::Error::from)
+}
+
+/// Builds the options database (`options.json`) of the configured NixOS
+/// system, as used by the NixOS manual, and returns its path.
+pub fn build_options_json(config: &Config) -> io::Result<PathBuf> {
+    const ATTR: &str = "config.system.build.manual.optionsJSON";
+    let output = if config.is_flake() {
+        Command::new("nix")
+            .args(["build", "--no-link", "--print-out-paths"])
+            .arg(format!(
+                "{}#nixosConfigurations.\"{}\".{}",
+                config.nixos_root.display(),
+                config.flake_host(),
+                ATTR
+            ))
+            .output()?
+    } else {
+        Command::new("nix-build")
+            .args(["<nixpkgs/nixos>", "--no-out-link", "-A", ATTR])
+            .arg("-I")
+            .arg(format!(
+                "nixos-config={}",
+                config.nixos_config_path().display()
+            ))
+            .output()?
+    };
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(stderr.trim().to_string()));
+    }
+
+    let out = String::from_utf8_lossy(&output.stdout);
+    let out = out.lines().last().unwrap_or_default().trim();
+    Ok(Path::new(out).join("share/doc/nixos/options.json"))
+}
+
+/// Walks an evaluated value, replacing what JSON cannot hold (functions,
+/// packages, package sets) with placeholders and anything that fails to
+/// evaluate with `<error>`.
+const EXPORT_SANITIZER: &str = r#"
+  sanitize = value:
+    let result = builtins.tryEval value; in
+    if !result.success then "<error>"
+    else let v = result.value; in
+      if builtins.isFunction v then "<function>"
+      else if builtins.isAttrs v then
+        if v.type or null == "derivation" then
+          let name = builtins.tryEval (v.name or "derivation"); in
+          if name.success then "<package ${name.value}>" else "<package>"
+        else if v ? stdenv && v ? callPackage then "<package set>"
+        else builtins.mapAttrs (_: sanitize) (builtins.removeAttrs v [ "_module" ])
+      else if builtins.isList v then map sanitize v
+      else if builtins.isPath v then toString v
+      else v;
+"#;
+
+/// Evaluates `config.<prefix>` (all of `config` without a prefix) of the
+/// configured NixOS system, with the `overlay` module added when given, and
+/// returns it as JSON.
+pub fn export_config(
+    config: &Config,
+    prefix: Option<&str>,
+    overlay: Option<&Path>,
+) -> io::Result<serde_json::Value> {
+    let attr = match prefix {
+        Some(prefix) => format!("config.{}", prefix),
+        None => "config".to_string(),
+    };
+
+    let output = if config.is_flake() {
+        let mut system = format!(
+            "(builtins.getFlake \"{}\").nixosConfigurations.\"{}\"",
+            config.nixos_root.display(),
+            config.flake_host()
+        );
+        if let Some(overlay) = overlay {
+            system = format!(
+                "({}.extendModules {{ modules = [ {} ]; }})",
+                system,
+                overlay.display()
+            );
+        }
+        let expr = format!(
+            "let system = {}; {} in sanitize system.{}",
+            system, EXPORT_SANITIZER, attr
+        );
+        Command::new("nix")
+            .args(["eval", "--json", "--impure", "--expr"])
+            .arg(expr)
+            .output()?
+    } else {
+        // The overlay imports the main configuration itself
+        let configuration = overlay
+            .map(Path::to_path_buf)
+            .unwrap_or_else(|| config.nixos_config_path());
+        let expr = format!(
+            "let system = import <nixpkgs/nixos> {{ configuration = \"{}\"; }}; {} in sanitize system.{}",
+            configuration.display(),
+            EXPORT_SANITIZER,
+            attr
+        );
+        Command::new("nix-instantiate")
+            .args(["--eval", "--strict", "--json", "--expr"])
+            .arg(expr)
+            .output()?
+    };
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(stderr.trim().to_string()));
+    }
+
+    serde_json::from_slice(&output.stdout).map_err(io::Error::from)
+}
+
+/// Evaluates the configured NixOS system down to its derivation, without
+/// building anything, to check that the configuration is valid.
+pub fn check_system(config: &Config) -> io::Result<()> {
+    let output = if config.is_flake() {
+        Command::new("nix")
+            .args(["eval", "--raw"])
+            .arg(format!(
+                "{}#nixosConfigurations.\"{}\".config.system.build.toplevel.drvPath",
+                config.nixos_root.display(),
+                config.flake_host()
+            ))
+            .output()?
+    } else {
+        Command::new("nix-instantiate")
+            .args(["<nixpkgs/nixos>", "-A", "system", "-I"])
+            .arg(format!(
+                "nixos-config={}",
+                config.nixos_config_path().display()
+            ))
+            .output()?
+    };
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(stderr.trim().to_string()));
+    }
+    Ok(())
+}
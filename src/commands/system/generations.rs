@@ -0,0 +1,173 @@
+//! NixOS system generations: the `system-<n>-link` entries of the system
+//! profile, and differences between their closures.
+
+use chrono::{DateTime, Local};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+pub const SYSTEM_PROFILE: &str = "/nix/var/nix/profiles/system";
+
+pub struct Generation {
+    pub number: u32,
+    pub created: Option<DateTime<Local>>,
+    pub nixos_version: Option<String>,
+    pub kernel: Option<String>,
+    /// Whether the system profile points at this generation
+    pub current: bool,
+}
+
+/// Path of the profile link for generation `number`.
+pub fn link(number: u32) -> PathBuf {
+    PathBuf::from(format!("{}-{}-link", SYSTEM_PROFILE, number))
+}
+
+/// Lists the system generations, oldest first.
+pub fn list() -> io::Result<Vec<Generation>> {
+    let profile = Path::new(SYSTEM_PROFILE);
+    let dir = profile.parent().unwrap_or(Path::new("/"));
+    let current = fs::read_link(profile).ok();
+
+    let mut generations = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        let Some(number) = link_number(&name) else {
+            continue;
+        };
+
+        let path = entry.path();
+        generations.push(Generation {
+            number,
+            created: fs::symlink_metadata(&path)
+                .and_then(|metadata| metadata.modified())
+                .ok()
+                .map(DateTime::<Local>::from),
+            nixos_version: fs::read_to_string(path.join("nixos-version"))
+                .ok()
+                .map(|version| version.trim().to_string()),
+            kernel: kernel_version(&path),
+            current: current.as_deref() == Some(Path::new(&name)),
+        });
+    }
+    generations.sort_by_key(|generation| generation.number);
+    Ok(generations)
+}
+
+/// The generation the system profile points at.
+pub fn current() -> Option<u32> {
+    let target = fs::read_link(SYSTEM_PROFILE).ok()?;
+    link_number(&target.file_name()?.to_string_lossy())
+}
+
+/// Parses the generation number from a `system-<n>-link` name.
+fn link_number(name: &str) -> Option<u32> {
+    name.strip_prefix("system-")
+        .and_then(|rest| rest.strip_suffix("-link"))
+        .and_then(|number| number.parse().ok())
+}
+
+/// Reads the kernel version from the generation's module tree, falling back
+/// to the kernel package name.
+fn kernel_version(generation: &Path) -> Option<String> {
+    let modules = generation.join("kernel-modules/lib/modules");
+    if let Some(version) = fs::read_dir(modules)
+        .ok()
+        .and_then(|mut entries| entries.next())
+        .and_then(|entry| entry.ok())
+    {
+        return Some(version.file_name().to_string_lossy().into_owned());
+    }
+
+    let kernel = fs::read_link(generation.join("kernel")).ok()?;
+    let package = kernel.parent()?.file_name()?.to_string_lossy().into_owned();
+    let name = package
+        .split_once('-')
+        .map_or(package.as_str(), |(_, name)| name);
+    Some(name.strip_prefix("linux-").unwrap_or(name).to_string())
+}
+
+/// How a package differs between two closures.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Changed,
+}
+
+pub struct ClosureChange {
+    pub kind: ChangeKind,
+    pub name: String,
+    /// Version change as reported by nix, e.g. `1.0 → 1.1`
+    pub versions: Option<String>,
+    /// Size change in KiB
+    pub size_kib: Option<f64>,
+}
+
+/// Compares the closures of two generations with `nix store diff-closures`.
+pub fn diff_closures(from: &Path, to: &Path) -> io::Result<Vec<ClosureChange>> {
+    let output = Command::new("nix")
+        .args([
+            "--extra-experimental-features",
+            "nix-command",
+            "store",
+            "diff-closures",
+        ])
+        .arg(from)
+        .arg(to)
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(io::Error::other(stderr.trim().to_string()));
+    }
+
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .filter_map(|line| parse_change(&strip_ansi(line)))
+        .collect())
+}
+
+/// Parses a `diff-closures` line such as `firefox: 119.0 → 120.0, +1234.5 KiB`.
+fn parse_change(line: &str) -> Option<ClosureChange> {
+    let (name, rest) = line.split_once(": ")?;
+    let mut versions = None;
+    let mut size_kib = None;
+    for part in rest.split(", ") {
+        if let Some(size) = part.strip_suffix(" KiB") {
+            size_kib = size.trim_start_matches('+').parse().ok();
+        } else {
+            versions = Some(part.to_string());
+        }
+    }
+
+    let kind = match versions.as_deref() {
+        Some(v) if v.starts_with('∅') => ChangeKind::Added,
+        Some(v) if v.ends_with('∅') => ChangeKind::Removed,
+        _ => ChangeKind::Changed,
+    };
+    Some(ClosureChange {
+        kind,
+        name: name.trim().to_string(),
+        versions,
+        size_kib,
+    })
+}
+
+/// Removes terminal color codes, which nix adds to version changes.
+fn strip_ansi(line: &str) -> String {
+    let mut result = String::new();
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        if c == '\x1b' {
+            for c in chars.by_ref() {
+                if c.is_ascii_alphabetic() {
+                    break;
+                }
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
@@ -0,0 +1,151 @@
+//! Commits of nx's configuration edits to the git repository holding the
+//! configuration.
+
+use crate::utils::privilege::Privilege;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+/// The top level of the git repository containing `dir`, if any.
+pub fn repo_root(dir: &Path) -> Option<PathBuf> {
+    let output = git(dir)
+        .args(["rev-parse", "--show-toplevel"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let root = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    (!root.is_empty()).then(|| PathBuf::from(root))
+}
+
+/// Lists modified, staged and untracked paths, relative to `root`; files in
+/// untracked directories are listed one by one. Paths with a component named
+/// in `ignored` are left out.
+pub fn dirty_files(root: &Path, ignored: &[&str]) -> io::Result<Vec<String>> {
+    let output = git(root)
+        .args(["status", "--porcelain", "-z", "--untracked-files=all"])
+        .output()?;
+    if !output.status.success() {
+        return Err(git_error("git status", &output.stderr));
+    }
+
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    let mut entries = stdout.split('\0');
+    let mut files = Vec::new();
+    while let Some(entry) = entries.next() {
+        let Some(path) = entry.get(3..) else {
+            continue;
+        };
+        // Renames and copies are followed by their source path
+        if entry.starts_with(['R', 'C']) {
+            entries.next();
+        }
+        let is_ignored = Path::new(path)
+            .components()
+            .any(|component| ignored.iter().any(|name| component.as_os_str() == *name));
+        if !is_ignored {
+            files.push(path.to_string());
+        }
+    }
+    Ok(files)
+}
+
+/// Commits the current content of `files`, adding them if untracked and
+/// recording the removal of deleted ones. Deleted files git never tracked are
+/// skipped.
+pub fn commit(
+    privilege: Privilege,
+    root: &Path,
+    files: &[PathBuf],
+    message: &str,
+) -> io::Result<()> {
+    let files: Vec<&PathBuf> = files
+        .iter()
+        .filter(|file| root.join(file).exists() || is_tracked(root, file))
+        .collect();
+    if files.is_empty() {
+        return Ok(());
+    }
+
+    let mut add = vec!["add".into(), "--all".into(), "--".into()];
+    add.extend(files.iter().map(|file| file.as_os_str().to_owned()));
+    run(privilege, root, &add)?;
+
+    let mut commit = vec![
+        "commit".into(),
+        "--quiet".into(),
+        "--message".into(),
+        message.into(),
+        "--".into(),
+    ];
+    commit.extend(files.iter().map(|file| file.as_os_str().to_owned()));
+    run(privilege, root, &commit)
+}
+
+fn is_tracked(root: &Path, file: &Path) -> bool {
+    git(root)
+        .args(["ls-files", "--error-unmatch", "--"])
+        .arg(file)
+        .output()
+        .is_ok_and(|output| output.status.success())
+}
+
+/// Points the tag `name` at HEAD, moving it if it exists.
+pub fn tag(privilege: Privilege, root: &Path, name: &str) -> io::Result<()> {
+    run(
+        privilege,
+        root,
+        &["tag".into(), "--force".into(), name.into()],
+    )
+}
+
+fn git(root: &Path) -> Command {
+    let mut command = Command::new("git");
+    // The configuration usually belongs to root; reading it as another user
+    // trips git's ownership check
+    command
+        .arg("-C")
+        .arg(root)
+        .arg("-c")
+        .arg(format!("safe.directory={}", root.display()));
+    command
+}
+
+/// Runs a git command that writes to the repository, as root if the current
+/// user may not write there.
+fn run(privilege: Privilege, root: &Path, args: &[std::ffi::OsString]) -> io::Result<()> {
+    let output = git(root).args(args).output()?;
+    if output.status.success() {
+        return Ok(());
+    }
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    if privilege == Privilege::None || !stderr.contains("Permission denied") {
+        return Err(git_error(
+            &format!("git {}", args[0].to_string_lossy()),
+            &output.stderr,
+        ));
+    }
+
+    let output = privilege
+        .command("git")
+        .arg("-C")
+        .arg(root)
+        .args(args)
+        .output()?;
+    if !output.status.success() {
+        return Err(git_error(
+            &format!("git {}", args[0].to_string_lossy()),
+            &output.stderr,
+        ));
+    }
+    Ok(())
+}
+
+fn git_error(command: &str, stderr: &[u8]) -> io::Error {
+    io::Error::other(format!(
+        "{} failed: {}",
+        command,
+        String::from_utf8_lossy(stderr).trim()
+    ))
+}
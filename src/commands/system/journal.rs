@@ -0,0 +1,253 @@
+//! Journal of system changes owned by nx.
+//!
+//! Changes are recorded as structured entries and the module applying them is
+//! regenerated on every change, so individual entries can be listed and
+//! dropped. Journals back both the temporary overlay and the nx-managed module.
+
+use super::editor::{self, Change, EditError, SYSTEM_PACKAGES};
+use crate::utils::privilege::Privilege;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::Path;
+
+#[derive(Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Debug)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    AddPackage,
+    SetOption,
+    AddToList,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Entry {
+    pub op: Operation,
+    pub path: String,
+    pub value: String,
+    pub timestamp: DateTime<Utc>,
+    pub user: String,
+}
+
+impl Entry {
+    fn new(op: Operation, path: &str, value: &str) -> Self {
+        Entry {
+            op,
+            path: path.to_string(),
+            value: value.to_string(),
+            timestamp: Utc::now(),
+            user: current_user(),
+        }
+    }
+
+    pub fn describe(&self) -> String {
+        match self.op {
+            Operation::AddPackage => format!("add package {}", self.value),
+            Operation::SetOption => format!("set {} = {}", self.path, self.value),
+            Operation::AddToList => format!("add {} to {}", self.value, self.path),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+pub struct Journal {
+    pub entries: Vec<Entry>,
+}
+
+impl Journal {
+    /// Loads the journal, importing bindings from a module written by an
+    /// older nx that did not keep a journal.
+    pub fn load(path: &Path, legacy_module: Option<&Path>) -> io::Result<Self> {
+        if path.exists() {
+            let content = fs::read_to_string(path)?;
+            return serde_json::from_str(&content).map_err(io::Error::from);
+        }
+
+        let mut journal = Journal::default();
+        if let Some(legacy_overlay) = legacy_module.filter(|path| path.exists()) {
+            let content = fs::read_to_string(legacy_overlay)?;
+            for (path, value) in editor::bindings(&content)? {
+                if path == SYSTEM_PACKAGES {
+                    for package in editor::list_elements(&value) {
+                        journal.add_package(&package);
+                    }
+                } else if path != "imports" {
+                    journal.set_option(&path, &editor::strip_force(&value));
+                }
+            }
+        }
+        Ok(journal)
+    }
+
+    pub fn save(&self, path: &Path, privilege: Privilege) -> io::Result<()> {
+        privilege.write(path, serde_json::to_string_pretty(self)?.as_bytes())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn add_package(&mut self, package: &str) -> Change {
+        if self
+            .entries
+            .iter()
+            .any(|entry| entry.op == Operation::AddPackage && entry.value == package)
+        {
+            return Change::Unchanged;
+        }
+        self.entries
+            .push(Entry::new(Operation::AddPackage, SYSTEM_PACKAGES, package));
+        Change::Added
+    }
+
+    pub fn set_option(&mut self, path: &str, value: &str) -> Change {
+        match self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.op == Operation::SetOption && entry.path == path)
+        {
+            Some(entry) if entry.value == value => Change::Unchanged,
+            Some(entry) => {
+                *entry = Entry::new(Operation::SetOption, path, value);
+                Change::Updated
+            }
+            None => {
+                self.entries
+                    .push(Entry::new(Operation::SetOption, path, value));
+                Change::Added
+            }
+        }
+    }
+
+    pub fn add_to_list(&mut self, path: &str, element: &str) -> Change {
+        if self.entries.iter().any(|entry| {
+            entry.op == Operation::AddToList && entry.path == path && entry.value == element
+        }) {
+            return Change::Unchanged;
+        }
+        self.entries
+            .push(Entry::new(Operation::AddToList, path, element));
+        Change::Added
+    }
+
+    pub fn remove_package(&mut self, package: &str) -> Change {
+        self.retain(|entry| !(entry.op == Operation::AddPackage && entry.value == package))
+    }
+
+    pub fn remove_option(&mut self, path: &str) -> Change {
+        self.retain(|entry| !(entry.op == Operation::SetOption && entry.path == path))
+    }
+
+    pub fn remove_from_list(&mut self, path: &str, element: &str) -> Change {
+        self.retain(|entry| {
+            !(entry.op == Operation::AddToList && entry.path == path && entry.value == element)
+        })
+    }
+
+    /// Removes the entry at the 1-based position `number`.
+    pub fn drop_entry(&mut self, number: usize) -> Option<Entry> {
+        if number == 0 || number > self.entries.len() {
+            return None;
+        }
+        Some(self.entries.remove(number - 1))
+    }
+
+    /// Renders the temporary overlay module, importing `base` when given.
+    /// Scalar values are forced so they override the base configuration.
+    pub fn render(&self, base: Option<&Path>) -> Result<String, EditError> {
+        let imports = base
+            .map(|base| format!("  imports = [ {} ];\n", base.display()))
+            .unwrap_or_default();
+        let header = format!(
+            "# Temporary NixOS configuration changes, generated by nx.\n\
+             # Use 'nx sys show' and 'nx sys drop' instead of editing this file.\n\
+             {{ config, lib, pkgs, ... }}:\n{{\n{}}}\n",
+            imports
+        );
+        self.render_onto(header, overlay_value)
+    }
+
+    /// Renders the permanent nx-managed module.
+    pub fn render_managed(&self) -> Result<String, EditError> {
+        let header = "# NixOS configuration managed by nx. Do not edit: this file is regenerated\n\
+                      # from .nx-managed.json on every change.\n\
+                      { config, lib, pkgs, ... }:\n{\n}\n"
+            .to_string();
+        self.render_onto(header, str::to_string)
+    }
+
+    fn render_onto(&self, header: String, value: fn(&str) -> String) -> Result<String, EditError> {
+        let mut content = header;
+        for entry in &self.entries {
+            content = match entry.op {
+                Operation::AddPackage => editor::add_package(&content, &entry.value)?.0,
+                Operation::SetOption => {
+                    editor::set_option(&content, &entry.path, &value(&entry.value))?.0
+                }
+                Operation::AddToList => editor::add_to_list(&content, &entry.path, &entry.value)?.0,
+            };
+        }
+        Ok(content)
+    }
+
+    fn retain<F: Fn(&Entry) -> bool>(&mut self, keep: F) -> Change {
+        let before = self.entries.len();
+        self.entries.retain(|entry| keep(entry));
+        if self.entries.len() == before {
+            Change::Unchanged
+        } else {
+            Change::Removed
+        }
+    }
+}
+
+/// Wraps values that would conflict with the main configuration in
+/// `lib.mkForce` so the overlay takes precedence.
+fn overlay_value(value: &str) -> String {
+    if editor::is_mergeable(value) {
+        value.to_string()
+    } else {
+        format!("lib.mkForce ({})", value)
+    }
+}
+
+pub fn current_user() -> String {
+    std::env::var("SUDO_USER")
+        .or_else(|_| std::env::var("USER"))
+        .unwrap_or_else(|_| "unknown".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn list_entries_render_as_valid_nix() {
+        let mut journal = Journal::default();
+        assert_eq!(
+            journal.add_to_list("networking.firewall.allowedTCPPorts", "80"),
+            Change::Added
+        );
+        journal.add_to_list("networking.firewall.allowedTCPPorts", "-1");
+        journal.add_to_list("environment.systemPackages", "pkgs.callPackage ./x.nix {}");
+        assert_eq!(
+            journal.add_to_list("networking.firewall.allowedTCPPorts", "80"),
+            Change::Unchanged
+        );
+
+        let overlay = journal.render(None).unwrap();
+        assert!(overlay.contains("80\n    (-1)"), "{}", overlay);
+        assert!(
+            overlay.contains("(pkgs.callPackage ./x.nix {})"),
+            "{}",
+            overlay
+        );
+        editor::parse(&overlay).unwrap();
+
+        assert_eq!(
+            journal.remove_from_list("networking.firewall.allowedTCPPorts", "-1"),
+            Change::Removed
+        );
+        assert!(!journal.render_managed().unwrap().contains("(-1)"));
+    }
+}
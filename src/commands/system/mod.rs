@@ -0,0 +1,2256 @@
+mod backup;
+mod diff;
+mod editor;
+mod eval;
+mod generations;
+mod git;
+mod journal;
+mod modules;
+mod options;
+mod progress;
+mod settings;
+mod snapshot;
+
+use crate::cli::{RebuildMode, SystemCommands};
+use crate::commands::log::Recorder;
+use crate::config::audit;
+use crate::config::store::Config;
+use crate::utils::lock::OperationLock;
+use backup::BackupStore;
+use editor::{Change, EditError, SYSTEM_PACKAGES};
+use journal::Journal;
+use options::{Lookup, OptionsDb};
+use serde::Serialize;
+use snapshot::SnapshotStore;
+use std::fs;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+use std::process::{Command, ExitStatus};
+
+// nx's own files, kept in the NixOS configuration root
+const TEMP_CONFIG_FILE: &str = ".nx-temp.nix";
+const JOURNAL_FILE: &str = ".nx-pending.json";
+const BACKUP_DIR: &str = ".nx-backups";
+const LOCK_FILE: &str = ".nx.lock";
+const SNAPSHOT_DIR: &str = ".nx-snapshots";
+/// nx's scratch files in the configuration root, which never go into git
+const PRIVATE_FILES: [&str; 5] = [
+    TEMP_CONFIG_FILE,
+    JOURNAL_FILE,
+    BACKUP_DIR,
+    LOCK_FILE,
+    SNAPSHOT_DIR,
+];
+// The nx-managed module and its state, kept next to the configuration file
+const MANAGED_MODULE_FILE: &str = "nx-managed.nix";
+const MANAGED_STATE_FILE: &str = ".nx-managed.json";
+
+pub fn execute(
+    command: SystemCommands,
+    wait: bool,
+    allow_dirty: bool,
+    passthrough_args: &[String],
+    config: &Config,
+) -> i32 {
+    if is_read_only(&command) {
+        return run(command, passthrough_args, config);
+    }
+    let _lock = match acquire_lock(wait, config) {
+        Ok(lock) => lock,
+        Err(e) => {
+            eprintln!("Failed to start system operation: {}", e);
+            return 1;
+        }
+    };
+
+    let recorder = Recorder::new(false, config);
+    let code = run_edit(command, allow_dirty, passthrough_args, config);
+    recorder.finish(code, None, config);
+    code
+}
+
+/// Runs a command that edits the configuration, committing its changes.
+fn run_edit(
+    command: SystemCommands,
+    allow_dirty: bool,
+    passthrough_args: &[String],
+    config: &Config,
+) -> i32 {
+    // A restore's rebuild runs once the restored files are committed, so the
+    // generation tag lands on that commit
+    let rebuild_after = matches!(command, SystemCommands::Restore { rebuild: true, .. });
+    let code = match commit_message(&command) {
+        Some(message) => with_git_commit(config, allow_dirty, &message, || {
+            run(command, passthrough_args, config)
+        }),
+        None => run(command, passthrough_args, config),
+    };
+    if code != 0 || !rebuild_after {
+        return code;
+    }
+    rebuild_system(RebuildMode::Switch, &[], config)
+}
+
+fn run(command: SystemCommands, passthrough_args: &[String], config: &Config) -> i32 {
+    match command {
+        SystemCommands::Package {
+            package,
+            permanent,
+            file,
+        } => {
+            if permanent {
+                add_system_package(&package, file.as_deref(), config)
+            } else {
+                add_temp_package(&package, config)
+            }
+        }
+        SystemCommands::Enable {
+            program,
+            permanent,
+            file,
+        } => {
+            let option = format!("{}.enable", program);
+            if permanent {
+                set_nixos_option(&option, "true", file.as_deref(), config)
+            } else {
+                set_temp_option(&option, "true", config)
+            }
+        }
+        SystemCommands::SetOption {
+            path,
+            value,
+            permanent,
+            file,
+            raw,
+        } => {
+            let value = match prepare_value(&path, &value, raw, false, config) {
+                Ok(value) => value,
+                Err(e) => {
+                    eprintln!("Failed to set {}: {}", path, e);
+                    return 1;
+                }
+            };
+            if permanent {
+                set_nixos_option(&path, &value, file.as_deref(), config)
+            } else {
+                set_temp_option(&path, &value, config)
+            }
+        }
+        SystemCommands::AddTo {
+            path,
+            element,
+            permanent,
+            file,
+            raw,
+        } => match prepare_value(&path, &element, raw, true, config) {
+            Ok(element) => add_to_list(&path, &element, permanent, file.as_deref(), config),
+            Err(e) => {
+                eprintln!("Failed to add to {}: {}", path, e);
+                1
+            }
+        },
+        SystemCommands::RemoveFrom {
+            path,
+            element,
+            permanent,
+            raw,
+        } => match prepare_value(&path, &element, raw, true, config) {
+            Ok(element) => remove_from_list(&path, &element, permanent, config),
+            Err(e) => {
+                eprintln!("Failed to remove from {}: {}", path, e);
+                1
+            }
+        },
+        SystemCommands::ImportSettings {
+            path,
+            settings,
+            permanent,
+            file,
+        } => import_settings(&path, &settings, permanent, file.as_deref(), config),
+        SystemCommands::RemovePackage { package, permanent } => {
+            remove_system_package(&package, permanent, config)
+        }
+        SystemCommands::Disable {
+            program,
+            remove,
+            permanent,
+        } => disable_program(&program, remove, permanent, config),
+        SystemCommands::UnsetOption { path, permanent } => {
+            unset_nixos_option(&path, permanent, config)
+        }
+        SystemCommands::GetOption { path, json } => get_nixos_option(&path, json, config),
+        SystemCommands::Apply => apply_temp_changes(config),
+        SystemCommands::Commit => commit_temp_changes(config),
+        SystemCommands::Discard => discard_temp_changes(config),
+        SystemCommands::Show { json } => show_pending_changes(json, config),
+        SystemCommands::Export {
+            prefix,
+            json,
+            pending,
+        } => export_configuration(prefix.as_deref(), json, pending, config),
+        SystemCommands::Edit { file } => edit_configuration(file.as_deref(), config),
+        SystemCommands::Rebuild { mode } => rebuild_system(mode, passthrough_args, config),
+        SystemCommands::History => show_history(),
+        SystemCommands::Rollback { generation } => rollback(generation, config),
+        SystemCommands::GenDiff { from, to } => show_generation_diff(from, to),
+        SystemCommands::Diff { eval } => show_diff(eval, config),
+        SystemCommands::Backups => list_backups(config),
+        SystemCommands::RestoreBackup { id } => restore_backup(&id, config),
+        SystemCommands::Snapshot { label, force } => create_snapshot(&label, force, config),
+        SystemCommands::Snapshots => list_snapshots(config),
+        SystemCommands::Restore { label, rebuild } => restore_snapshot(&label, rebuild, config),
+        SystemCommands::Drop { number } => drop_pending_change(number, config),
+    }
+}
+
+pub fn is_read_only(command: &SystemCommands) -> bool {
+    matches!(
+        command,
+        SystemCommands::GetOption { .. }
+            | SystemCommands::Show { .. }
+            | SystemCommands::Export { .. }
+            | SystemCommands::Diff { .. }
+            | SystemCommands::History
+            | SystemCommands::GenDiff { .. }
+            | SystemCommands::Backups
+            | SystemCommands::Snapshots
+    )
+}
+
+/// Describes a command that may edit the configuration, for its git commit.
+fn commit_message(command: &SystemCommands) -> Option<String> {
+    let message = match command {
+        SystemCommands::Package { package, .. } => format!("add package {}", package),
+        SystemCommands::Enable { program, .. } => format!("enable {}", program),
+        SystemCommands::SetOption { path, .. } => format!("set {}", path),
+        SystemCommands::AddTo { path, element, .. } => format!("add {} to {}", element, path),
+        SystemCommands::RemoveFrom { path, element, .. } => {
+            format!("remove {} from {}", element, path)
+        }
+        SystemCommands::ImportSettings { path, settings, .. } => {
+            format!("import {} into {}", settings.display(), path)
+        }
+        SystemCommands::RemovePackage { package, .. } => format!("remove package {}", package),
+        SystemCommands::Disable { program, .. } => format!("disable {}", program),
+        SystemCommands::UnsetOption { path, .. } => format!("unset {}", path),
+        SystemCommands::Commit => "commit temporary changes".to_string(),
+        SystemCommands::Edit { file } => match file {
+            Some(file) => format!("edit {}", file.display()),
+            None => "edit configuration".to_string(),
+        },
+        SystemCommands::RestoreBackup { id } => format!("restore backup {}", id),
+        SystemCommands::Restore { label, .. } => format!("restore snapshot {}", label),
+        _ => return None,
+    };
+    Some(message)
+}
+
+/// Runs `edit`, then commits the files it wrote in the repository with
+/// `nx: <message>` when git commits are enabled. Refuses to run on a
+/// repository with uncommitted changes unless `allow_dirty` is set.
+pub fn with_git_commit<F>(config: &Config, allow_dirty: bool, message: &str, edit: F) -> i32
+where
+    F: FnOnce() -> i32,
+{
+    if !config.git_commit {
+        return edit();
+    }
+    let Some(root) = git::repo_root(&config.nixos_root) else {
+        eprintln!(
+            "Warning: {} is not in a git repository; not committing.",
+            config.nixos_root.display()
+        );
+        return edit();
+    };
+
+    let dirty = match git::dirty_files(&root, &PRIVATE_FILES) {
+        Ok(dirty) if !dirty.is_empty() && !allow_dirty => {
+            eprintln!(
+                "{} has uncommitted changes: {}",
+                root.display(),
+                dirty.join(", ")
+            );
+            eprintln!("Commit or stash them first, or pass --allow-dirty.");
+            return 1;
+        }
+        Ok(dirty) => dirty,
+        Err(e) => {
+            eprintln!("Failed to check the git repository: {}", e);
+            return 1;
+        }
+    };
+
+    // With --allow-dirty, files that were already modified only count as
+    // written by the edit if their content changes
+    let before: Vec<(String, Option<Vec<u8>>)> = dirty
+        .into_iter()
+        .map(|path| {
+            let content = fs::read(root.join(&path)).ok();
+            (path, content)
+        })
+        .collect();
+    let code = edit();
+    if code != 0 {
+        return code;
+    }
+
+    // Everything the edit wrote or deleted now shows up in git status, also
+    // files outside the import tree such as other flake hosts
+    let changed: Vec<PathBuf> = match git::dirty_files(&root, &PRIVATE_FILES) {
+        Ok(dirty) => dirty
+            .into_iter()
+            .filter(
+                |path| match before.iter().find(|(dirty, _)| dirty == path) {
+                    Some((_, content)) => fs::read(root.join(path)).ok() != *content,
+                    None => true,
+                },
+            )
+            .map(PathBuf::from)
+            .collect(),
+        Err(e) => {
+            eprintln!("Warning: could not commit the change to git: {}", e);
+            return code;
+        }
+    };
+    if changed.is_empty() {
+        return code;
+    }
+    let message = format!("nx: {}", message);
+    match git::commit(config.privilege, &root, &changed, &message) {
+        Ok(()) => println!("Committed to git: {}", message),
+        Err(e) => eprintln!("Warning: could not commit the change to git: {}", e),
+    }
+    code
+}
+
+/// Tags the commit the current generation was built from as
+/// `nixos-generation-<n>`.
+fn tag_generation(config: &Config) -> std::io::Result<()> {
+    let (Some(root), Some(generation)) =
+        (git::repo_root(&config.nixos_root), generations::current())
+    else {
+        return Ok(());
+    };
+    if !git::dirty_files(&root, &PRIVATE_FILES)?.is_empty() {
+        eprintln!(
+            "Warning: not tagging generation {}: the configuration has uncommitted changes.",
+            generation
+        );
+        return Ok(());
+    }
+    git::tag(
+        config.privilege,
+        &root,
+        &format!("nixos-generation-{}", generation),
+    )
+}
+
+/// Takes the lock serializing configuration edits and rebuilds across nx processes.
+pub fn acquire_lock(wait: bool, config: &Config) -> std::io::Result<OperationLock> {
+    let path = config.nixos_root.join(LOCK_FILE);
+    match OperationLock::acquire(&path, wait) {
+        // Created in place rather than written and renamed, which would
+        // replace a file another process may already have locked
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied && !path.exists() => {
+            config.privilege.create_file(&path)?;
+            OperationLock::acquire(&path, wait)
+        }
+        result => result,
+    }
+}
+
+pub fn get_config_path(config: &Config) -> PathBuf {
+    config.nixos_config_path()
+}
+
+/// Chooses the module file an edit of `option` should go to: `file` if given
+/// (relative to the configuration root), otherwise the module that already
+/// defines the option or an attribute set above it, and the configuration
+/// file when none does.
+fn get_edit_path(config: &Config, option: &str, file: Option<&Path>) -> PathBuf {
+    let files = get_hand_written_modules(config);
+    if let Some(file) = file {
+        let path = config.nixos_root.join(file);
+        if !files.contains(&path) {
+            eprintln!(
+                "Warning: {} is not imported by {}.",
+                path.display(),
+                get_config_path(config).display()
+            );
+        }
+        return path;
+    }
+    modules::definition_file(&files, option).unwrap_or_else(|| get_config_path(config))
+}
+
+/// The configuration's modules, excluding the generated nx-managed module.
+fn get_hand_written_modules(config: &Config) -> Vec<PathBuf> {
+    let managed = get_managed_path(config);
+    let mut files = modules::module_files(&get_config_path(config));
+    files.retain(|file| *file != managed);
+    files
+}
+
+fn get_managed_path(config: &Config) -> PathBuf {
+    get_config_dir(config).join(MANAGED_MODULE_FILE)
+}
+
+fn get_managed_state_path(config: &Config) -> PathBuf {
+    get_config_dir(config).join(MANAGED_STATE_FILE)
+}
+
+fn get_config_dir(config: &Config) -> PathBuf {
+    get_config_path(config)
+        .parent()
+        .map(Path::to_path_buf)
+        .unwrap_or_else(|| config.nixos_root.clone())
+}
+
+/// nx's files holding temporary changes: the journal and the overlay
+/// rendered from it.
+pub fn get_pending_files(config: &Config) -> Vec<PathBuf> {
+    vec![get_journal_path(config), get_temp_path(config)]
+}
+
+fn get_temp_path(config: &Config) -> PathBuf {
+    config.nixos_root.join(TEMP_CONFIG_FILE)
+}
+
+fn get_backup_store(config: &Config) -> BackupStore {
+    BackupStore::new(config.nixos_root.join(BACKUP_DIR), config.privilege)
+}
+
+fn get_snapshot_store(config: &Config) -> SnapshotStore {
+    SnapshotStore::new(config.nixos_root.join(SNAPSHOT_DIR), config.privilege)
+}
+
+fn get_journal_path(config: &Config) -> PathBuf {
+    config.nixos_root.join(JOURNAL_FILE)
+}
+
+/// Runs `nixos-rebuild <action>` for the configured system.
+pub fn nixos_rebuild(
+    config: &Config,
+    action: &str,
+    extra_args: &[String],
+) -> std::io::Result<ExitStatus> {
+    let mut command = config.privilege.command("nixos-rebuild");
+    command.arg(action).args(extra_args);
+    if config.is_flake() {
+        command.args(["--flake", &config.flake_ref()]);
+    } else {
+        command.arg("-I").arg(format!(
+            "nixos-config={}",
+            get_config_path(config).display()
+        ));
+    }
+    // Progress rendering needs a terminal; otherwise nix's plain output is
+    // easier to read in logs
+    let status = if std::io::stderr().is_terminal() {
+        command.args(["--log-format", "internal-json", "-v"]);
+        progress::run(command)?
+    } else {
+        command.status()?
+    };
+
+    if status.success() && matches!(action, "switch" | "boot") {
+        if let Err(e) = record_rebuilt_state(config) {
+            eprintln!("Warning: could not record the rebuilt configuration: {}", e);
+        }
+        if config.git_commit && config.git_tag {
+            if let Err(e) = tag_generation(config) {
+                eprintln!("Warning: could not tag the generation in git: {}", e);
+            }
+        }
+    }
+    Ok(status)
+}
+
+/// Keeps a copy of the configuration files as built, which `nx sys diff`
+/// compares against.
+fn record_rebuilt_state(config: &Config) -> std::io::Result<()> {
+    let files = modules::module_files(&get_config_path(config))
+        .into_iter()
+        .map(|file| fs::read_to_string(&file).map(|content| (file, content)))
+        .collect::<std::io::Result<Vec<_>>>()?;
+    get_backup_store(config).record_rebuilt(&files)
+}
+
+pub fn switch_hint(config: &Config) -> String {
+    let command = if config.is_flake() {
+        format!("nixos-rebuild switch --flake {}", config.flake_ref())
+    } else {
+        "nixos-rebuild switch".to_string()
+    };
+    format!(
+        "Run '{}' to apply.",
+        config.privilege.command_line(&command)
+    )
+}
+
+fn load_journal(config: &Config) -> std::io::Result<Journal> {
+    Journal::load(&get_journal_path(config), Some(&get_temp_path(config)))
+}
+
+/// Writes the journal and regenerates the temporary overlay from it, removing
+/// both once no changes are pending.
+fn save_journal(config: &Config, journal: &Journal) -> std::io::Result<()> {
+    if journal.is_empty() {
+        for path in [get_journal_path(config), get_temp_path(config)] {
+            if path.exists() {
+                config.privilege.remove_file(&path)?;
+            }
+        }
+        return Ok(());
+    }
+
+    // A flake host already includes its configuration, so only channel-based
+    // overlays need to import it
+    let config_path = get_config_path(config);
+    let base = (!config.is_flake()).then_some(config_path.as_path());
+    journal.save(&get_journal_path(config), config.privilege)?;
+    config
+        .privilege
+        .write(&get_temp_path(config), journal.render(base)?.as_bytes())
+}
+
+/// Applies `update` to the pending-change journal, saving it if anything changed.
+fn update_pending<F>(config: &Config, update: F) -> std::io::Result<Change>
+where
+    F: FnOnce(&mut Journal) -> Change,
+{
+    let mut journal = load_journal(config)?;
+    let change = update(&mut journal);
+    if change != Change::Unchanged {
+        save_journal(config, &journal)?;
+    }
+    Ok(change)
+}
+
+/// Converts a command-line value for `path` (or, with `element`, for an
+/// element of the list at `path`) and checks that it is a usable Nix
+/// expression. `raw` values are only checked.
+fn prepare_value(
+    path: &str,
+    value: &str,
+    raw: bool,
+    element: bool,
+    config: &Config,
+) -> Result<String, String> {
+    let value = if raw {
+        value.to_string()
+    } else {
+        coerce_value(path, value, element, config)?
+    };
+    editor::check_value(&value).map_err(|e| e.to_string())?;
+    Ok(value)
+}
+
+/// Converts a command-line value to the Nix expression the option's declared
+/// type expects, rejecting options that do not exist. Without an options
+/// database the value is used as given.
+fn coerce_value(path: &str, value: &str, element: bool, config: &Config) -> Result<String, String> {
+    let db = match load_options(config) {
+        Ok(db) => db,
+        Err(e) => {
+            eprintln!(
+                "Warning: could not load the NixOS options database, using the value as given: {}",
+                e
+            );
+            return Ok(value.to_string());
+        }
+    };
+
+    match db.lookup(path) {
+        Lookup::Option(info) if element => match info.ty.strip_prefix("list of ") {
+            Some(inner) => options::coerce(inner, value),
+            None => Err(format!("{} is not a list (type: {})", path, info.ty)),
+        },
+        Lookup::Option(info) => options::coerce(&info.ty, value),
+        Lookup::Nested(Some(ty)) if element => match ty.strip_prefix("list of ") {
+            Some(inner) => options::coerce(inner, value),
+            None => Err(format!("{} is not a list (type: {})", path, ty)),
+        },
+        Lookup::Nested(Some(ty)) => options::coerce(&ty, value),
+        Lookup::Prefix | Lookup::Nested(None) => Ok(value.to_string()),
+        Lookup::Unknown(suggestions) if suggestions.is_empty() => {
+            Err(format!("unknown option '{}'", path))
+        }
+        Lookup::Unknown(suggestions) => Err(format!(
+            "unknown option '{}'; did you mean {}?",
+            path,
+            suggestions
+                .iter()
+                .map(|name| format!("'{}'", name))
+                .collect::<Vec<_>>()
+                .join(", ")
+        )),
+    }
+}
+
+fn load_options(config: &Config) -> std::io::Result<OptionsDb> {
+    let path = match &config.options_json {
+        Some(path) => path.clone(),
+        None => {
+            println!("Building the NixOS options database...");
+            eval::build_options_json(config)?
+        }
+    };
+    OptionsDb::load(&path)
+}
+
+/// Applies `update` to the nx-managed module's state, regenerating the
+/// module and making sure the configuration imports it.
+fn update_managed<F>(config: &Config, update: F) -> std::io::Result<Change>
+where
+    F: FnOnce(&mut Journal) -> Change,
+{
+    let state_path = get_managed_state_path(config);
+    let mut state = Journal::load(&state_path, None)?;
+    let change = update(&mut state);
+    if change == Change::Unchanged {
+        return Ok(change);
+    }
+
+    state.save(&state_path, config.privilege)?;
+    let module_path = get_managed_path(config);
+    let module = state.render_managed()?;
+    if module_path.exists() {
+        modify_config_file(config, &module_path, |_| Ok((module, Change::Updated)))?;
+    } else {
+        config.privilege.write(&module_path, module.as_bytes())?;
+        if config.is_flake() {
+            println!(
+                "Created {}. Add it to git so the flake can see it.",
+                module_path.display()
+            );
+        }
+    }
+
+    let import = format!("./{}", MANAGED_MODULE_FILE);
+    modify_config_file(config, &get_config_path(config), |content| {
+        editor::add_import(content, &import)
+    })?;
+    Ok(change)
+}
+
+/// Applies a permanent edit of `option`: to the nx-managed module's state
+/// through `managed` when that module is in use, otherwise to the
+/// hand-written module chosen by `get_edit_path` through `edit`. Returns the
+/// change and the file it went to.
+fn edit_permanent<E, M>(
+    config: &Config,
+    option: &str,
+    file: Option<&Path>,
+    edit: E,
+    managed: M,
+) -> std::io::Result<(Change, PathBuf)>
+where
+    E: FnOnce(&str) -> Result<(String, Change), EditError>,
+    M: FnOnce(&mut Journal) -> Change,
+{
+    if config.managed_module && file.is_none() {
+        return Ok((update_managed(config, managed)?, get_managed_path(config)));
+    }
+    let path = get_edit_path(config, option, file);
+    Ok((modify_config_file(config, &path, edit)?, path))
+}
+
+/// Finds a hand-written module that defines `option`, a value above it or
+/// values below it, any of which the nx-managed module would conflict with.
+fn hand_written_definition(config: &Config, option: &str) -> Option<PathBuf> {
+    let target = editor::split_path(option);
+    get_hand_written_modules(config).into_iter().find(|file| {
+        let Ok(bindings) = fs::read_to_string(file).map(|content| editor::bindings(&content))
+        else {
+            return false;
+        };
+        bindings.unwrap_or_default().iter().any(|(binding, _)| {
+            let names = editor::split_path(binding);
+            names.starts_with(&target) || target.starts_with(&names)
+        })
+    })
+}
+
+/// The value the nx-managed module stores for `option`. Where a
+/// hand-written module also defines the option and the two definitions would
+/// not merge, the value is wrapped in `lib.mkForce` so the rebuild does not
+/// fail with conflicting definitions.
+fn managed_value(config: &Config, option: &str, value: &str, file: Option<&Path>) -> String {
+    if !config.managed_module || file.is_some() || editor::is_mergeable(value) {
+        return value.to_string();
+    }
+    match hand_written_definition(config, option) {
+        Some(path) => {
+            println!(
+                "Note: {} is also defined in {}; the nx-managed value overrides it.",
+                option,
+                path.display()
+            );
+            format!("lib.mkForce ({})", value)
+        }
+        None => value.to_string(),
+    }
+}
+
+/// Applies a permanent removal: in managed mode to the nx-managed module's
+/// state through `managed` and to the hand-written modules through
+/// `remove`, since the configuration may define the value in either.
+fn remove_permanent<R, M>(config: &Config, remove: R, managed: M) -> std::io::Result<Change>
+where
+    R: Fn(&str) -> Result<(String, Change), EditError>,
+    M: FnOnce(&mut Journal) -> Change,
+{
+    let mut result = Change::Unchanged;
+    if config.managed_module {
+        result = update_managed(config, managed)?;
+    }
+    if remove_from_modules(config, remove)? == Change::Removed {
+        result = Change::Removed;
+    }
+    Ok(result)
+}
+
+pub fn add_system_package(package: &str, file: Option<&Path>, config: &Config) -> i32 {
+    match edit_permanent(
+        config,
+        SYSTEM_PACKAGES,
+        file,
+        |content| editor::add_package(content, package),
+        |state| state.add_package(package),
+    ) {
+        Ok((Change::Unchanged, _)) => {
+            println!("{} is already in system packages.", package);
+            0
+        }
+        Ok((_, path)) => {
+            println!(
+                "Added {} to system packages in {}. {}",
+                package,
+                path.display(),
+                switch_hint(config)
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to modify configuration: {}", e);
+            1
+        }
+    }
+}
+
+fn add_temp_package(package: &str, config: &Config) -> i32 {
+    match update_pending(config, |journal| journal.add_package(package)) {
+        Ok(Change::Unchanged) => {
+            println!("{} is already in temporary configuration.", package);
+            0
+        }
+        Ok(_) => {
+            println!(
+                "Added {} to temporary configuration. Run 'nx sys apply' to apply changes.",
+                package
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to modify temporary configuration: {}", e);
+            1
+        }
+    }
+}
+
+fn set_nixos_option(path: &str, value: &str, file: Option<&Path>, config: &Config) -> i32 {
+    let managed = managed_value(config, path, value, file);
+    match edit_permanent(
+        config,
+        path,
+        file,
+        |content| editor::set_option(content, path, value),
+        |state| state.set_option(path, &managed),
+    ) {
+        Ok((Change::Unchanged, _)) => {
+            println!("{} is already set to {}.", path, value);
+            0
+        }
+        Ok((change, file)) => {
+            println!(
+                "{} {} = {} in {}. {}",
+                if change == Change::Updated {
+                    "Updated"
+                } else {
+                    "Set"
+                },
+                path,
+                value,
+                file.display(),
+                switch_hint(config)
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to modify configuration: {}", e);
+            1
+        }
+    }
+}
+
+fn set_temp_option(path: &str, value: &str, config: &Config) -> i32 {
+    match update_pending(config, |journal| journal.set_option(path, value)) {
+        Ok(Change::Unchanged) => {
+            println!(
+                "{} is already set to {} in temporary configuration.",
+                path, value
+            );
+            0
+        }
+        Ok(change) => {
+            println!(
+                "{} {} = {} in temporary configuration. Run 'nx sys apply' to apply changes.",
+                if change == Change::Updated {
+                    "Updated"
+                } else {
+                    "Set"
+                },
+                path,
+                value
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to modify temporary configuration: {}", e);
+            1
+        }
+    }
+}
+
+fn add_to_list(
+    path: &str,
+    element: &str,
+    permanent: bool,
+    file: Option<&Path>,
+    config: &Config,
+) -> i32 {
+    let result = if permanent {
+        edit_permanent(
+            config,
+            path,
+            file,
+            |content| editor::add_to_list(content, path, element),
+            |state| state.add_to_list(path, element),
+        )
+        .map(|(change, file)| (change, file.display().to_string()))
+    } else {
+        update_pending(config, |journal| journal.add_to_list(path, element))
+            .map(|change| (change, "temporary configuration".to_string()))
+    };
+
+    match result {
+        Ok((Change::Unchanged, label)) => {
+            println!("{} is already in {} in {}.", element, path, label);
+            0
+        }
+        Ok((_, label)) => {
+            println!(
+                "Added {} to {} in {}. {}",
+                element,
+                path,
+                label,
+                apply_hint(permanent, config)
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to modify configuration: {}", e);
+            1
+        }
+    }
+}
+
+fn remove_from_list(path: &str, element: &str, permanent: bool, config: &Config) -> i32 {
+    let (label, result) = if permanent {
+        (
+            "system configuration",
+            remove_permanent(
+                config,
+                |content| editor::remove_from_list(content, path, element),
+                |state| state.remove_from_list(path, element),
+            ),
+        )
+    } else {
+        (
+            "temporary configuration",
+            update_pending(config, |journal| journal.remove_from_list(path, element)),
+        )
+    };
+
+    match result {
+        Ok(Change::Unchanged) => {
+            println!("{} is not in {} in the {}.", element, path, label);
+            0
+        }
+        Ok(_) => {
+            println!(
+                "Removed {} from {} in the {}. {}",
+                element,
+                path,
+                label,
+                apply_hint(permanent, config)
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to modify {}: {}", label, e);
+            1
+        }
+    }
+}
+
+fn import_settings(
+    path: &str,
+    settings_file: &Path,
+    permanent: bool,
+    file: Option<&Path>,
+    config: &Config,
+) -> i32 {
+    let data = match settings::load(settings_file) {
+        Ok(data) => data,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", settings_file.display(), e);
+            return 1;
+        }
+    };
+    let value = match prepare_value(path, &settings::to_nix(&data), false, false, config) {
+        Ok(value) => value,
+        Err(e) => {
+            eprintln!("Failed to import settings into {}: {}", path, e);
+            return 1;
+        }
+    };
+
+    if permanent && config.managed_module && file.is_none() {
+        if let Some(defined) = hand_written_definition(config, path) {
+            eprintln!(
+                "{} is already configured in {}; import with --file {} to merge the settings there.",
+                path,
+                defined.display(),
+                defined
+                    .strip_prefix(&config.nixos_root)
+                    .unwrap_or(&defined)
+                    .display()
+            );
+            return 1;
+        }
+    }
+
+    let result = if permanent {
+        edit_permanent(
+            config,
+            path,
+            file,
+            |content| settings::merge(content, path, &data),
+            |state| state.set_option(path, &value),
+        )
+        .map(|(change, file)| (change, file.display().to_string()))
+    } else {
+        update_pending(config, |journal| journal.set_option(path, &value))
+            .map(|change| (change, "temporary configuration".to_string()))
+    };
+
+    match result {
+        Ok((Change::Unchanged, label)) => {
+            println!(
+                "{} in {} already matches {}.",
+                path,
+                label,
+                settings_file.display()
+            );
+            0
+        }
+        Ok((_, label)) => {
+            println!(
+                "Imported {} into {} in {}. {}",
+                settings_file.display(),
+                path,
+                label,
+                apply_hint(permanent, config)
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to modify configuration: {}", e);
+            1
+        }
+    }
+}
+
+fn apply_hint(permanent: bool, config: &Config) -> String {
+    if permanent {
+        switch_hint(config)
+    } else {
+        "Run 'nx sys apply' to apply changes.".to_string()
+    }
+}
+
+fn remove_system_package(package: &str, permanent: bool, config: &Config) -> i32 {
+    let (label, result) = if permanent {
+        (
+            "system configuration",
+            remove_permanent(
+                config,
+                |content| editor::remove_package(content, package),
+                |state| state.remove_package(package),
+            ),
+        )
+    } else {
+        (
+            "temporary configuration",
+            update_pending(config, |journal| journal.remove_package(package)),
+        )
+    };
+
+    match result {
+        Ok(Change::Unchanged) => {
+            println!("{} is not in the {}.", package, label);
+            0
+        }
+        Ok(_) => {
+            println!(
+                "Removed {} from the {}. {}",
+                package,
+                label,
+                apply_hint(permanent, config)
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to modify {}: {}", label, e);
+            1
+        }
+    }
+}
+
+/// Applies a removal to every module of the configuration, reporting
+/// `Change::Removed` if any of them changed.
+fn remove_from_modules<F>(config: &Config, remove: F) -> std::io::Result<Change>
+where
+    F: Fn(&str) -> Result<(String, Change), EditError>,
+{
+    let mut result = Change::Unchanged;
+    for file in get_hand_written_modules(config) {
+        if modify_config_file(config, &file, &remove)? == Change::Removed {
+            result = Change::Removed;
+        }
+    }
+    Ok(result)
+}
+
+fn disable_program(program: &str, remove: bool, permanent: bool, config: &Config) -> i32 {
+    let option = format!("{}.enable", program);
+    let (label, result) = if permanent {
+        (
+            "system configuration",
+            if remove {
+                remove_permanent(
+                    config,
+                    |content| editor::remove_option(content, &option),
+                    |state| state.remove_option(&option),
+                )
+            } else {
+                let managed = managed_value(config, &option, "false", None);
+                edit_permanent(
+                    config,
+                    &option,
+                    None,
+                    |content| editor::update_option(content, &option, "false"),
+                    |state| state.set_option(&option, &managed),
+                )
+                .map(|(change, _)| change)
+            },
+        )
+    } else {
+        (
+            "temporary configuration",
+            update_pending(config, |journal| {
+                if remove {
+                    journal.remove_option(&option)
+                } else {
+                    journal.set_option(&option, "false")
+                }
+            }),
+        )
+    };
+
+    match result {
+        Ok(Change::Unchanged) => {
+            println!("{} is not enabled in the {}.", program, label);
+            0
+        }
+        Ok(_) => {
+            println!(
+                "Disabled {} in the {}. {}",
+                program,
+                label,
+                apply_hint(permanent, config)
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to modify {}: {}", label, e);
+            1
+        }
+    }
+}
+
+fn unset_nixos_option(option: &str, permanent: bool, config: &Config) -> i32 {
+    let (label, result) = if permanent {
+        (
+            "system configuration",
+            remove_permanent(
+                config,
+                |content| editor::remove_option(content, option),
+                |state| state.remove_option(option),
+            ),
+        )
+    } else {
+        (
+            "temporary configuration",
+            update_pending(config, |journal| journal.remove_option(option)),
+        )
+    };
+
+    match result {
+        Ok(Change::Unchanged) => {
+            println!("{} is not set in the {}.", option, label);
+            0
+        }
+        Ok(_) => {
+            println!(
+                "Unset {} in the {}. {}",
+                option,
+                label,
+                apply_hint(permanent, config)
+            );
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to modify {}: {}", label, e);
+            1
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct OptionDefinition {
+    file: PathBuf,
+    line: usize,
+    value: String,
+}
+
+#[derive(Serialize)]
+struct OptionReport {
+    option: String,
+    definitions: Vec<OptionDefinition>,
+    value: Option<serde_json::Value>,
+    error: Option<String>,
+}
+
+fn get_nixos_option(option: &str, json: bool, config: &Config) -> i32 {
+    let mut files = modules::module_files(&get_config_path(config));
+    files.push(get_temp_path(config));
+
+    let mut definitions = Vec::new();
+    for file in files {
+        if !file.exists() {
+            continue;
+        }
+        let found = fs::read_to_string(&file)
+            .map_err(|e| e.to_string())
+            .and_then(|content| editor::find_option(&content, option).map_err(|e| e.to_string()));
+        match found {
+            Ok(Some((line, value))) => definitions.push(OptionDefinition { file, line, value }),
+            Ok(None) => {}
+            Err(e) => {
+                eprintln!("Failed to read {}: {}", file.display(), e);
+                return 1;
+            }
+        }
+    }
+
+    let (value, error) = match eval::eval_option(config, option) {
+        Ok(value) => (Some(value), None),
+        Err(e) => (None, Some(e.to_string())),
+    };
+    let report = OptionReport {
+        option: option.to_string(),
+        definitions,
+        value,
+        error,
+    };
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).unwrap_or_default()
+        );
+    } else {
+        println!("{}", report.option);
+        if report.definitions.is_empty() {
+            println!("  Defined: not set in configuration");
+        }
+        for definition in &report.definitions {
+            println!(
+                "  Defined: {} (line {}): {}",
+                definition.file.display(),
+                definition.line,
+                definition.value
+            );
+        }
+        match (&report.value, &report.error) {
+            (Some(value), _) => println!("  Value: {}", value),
+            (None, Some(e)) => println!("  Value: failed to evaluate: {}", e),
+            (None, None) => {}
+        }
+    }
+
+    if report.value.is_some() || !report.definitions.is_empty() {
+        0
+    } else {
+        1
+    }
+}
+
+fn apply_temp_changes(config: &Config) -> i32 {
+    let journal = match load_journal(config) {
+        Ok(journal) => journal,
+        Err(e) => {
+            eprintln!("Failed to read pending changes: {}", e);
+            return 1;
+        }
+    };
+    if journal.is_empty() {
+        println!("No temporary changes to apply.");
+        return 0;
+    }
+
+    // Regenerate the overlay so it reflects the journal and the current base path
+    if let Err(e) = save_journal(config, &journal) {
+        eprintln!("Failed to write temporary configuration: {}", e);
+        return 1;
+    }
+    match activate_overlay(config) {
+        Ok(exit_status) => {
+            if exit_status.success() {
+                println!("Successfully applied temporary changes until the next reboot.");
+                println!("Run 'nx sys commit' to make them permanent.");
+                0
+            } else {
+                eprintln!("Failed to apply changes.");
+                1
+            }
+        }
+        Err(e) => {
+            eprintln!("Failed to run nixos-rebuild: {}", e);
+            1
+        }
+    }
+}
+
+/// Activates the system with the overlay applied, without adding a boot
+/// entry, so the changes are gone after a reboot.
+fn activate_overlay(config: &Config) -> std::io::Result<ExitStatus> {
+    let temp_path = get_temp_path(config);
+    if !config.is_flake() {
+        return config
+            .privilege
+            .command("nixos-rebuild")
+            .args(["test", "-I"])
+            .arg(format!("nixos-config={}", temp_path.display()))
+            .status();
+    }
+
+    // Flake hosts cannot take an extra module on the command line, so build
+    // the host extended with the overlay and activate it directly
+    let expr = format!(
+        "((builtins.getFlake \"{}\").nixosConfigurations.\"{}\".extendModules {{ modules = [ {} ]; }}).config.system.build.toplevel",
+        config.nixos_root.display(),
+        config.flake_host(),
+        temp_path.display()
+    );
+    let output = Command::new("nix")
+        .args([
+            "build",
+            "--impure",
+            "--no-link",
+            "--print-out-paths",
+            "--expr",
+        ])
+        .arg(&expr)
+        .stderr(std::process::Stdio::inherit())
+        .output()?;
+    if !output.status.success() {
+        return Ok(output.status);
+    }
+
+    let toplevel = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    config
+        .privilege
+        .command(&format!("{}/bin/switch-to-configuration", toplevel))
+        .arg("test")
+        .status()
+}
+
+/// Replays the pending changes onto the main configuration and clears them.
+fn commit_temp_changes(config: &Config) -> i32 {
+    let journal = match load_journal(config) {
+        Ok(journal) => journal,
+        Err(e) => {
+            eprintln!("Failed to read pending changes: {}", e);
+            return 1;
+        }
+    };
+    if journal.is_empty() {
+        println!("No temporary changes to commit.");
+        return 0;
+    }
+
+    for entry in &journal.entries {
+        let result = edit_permanent(
+            config,
+            &entry.path,
+            None,
+            |content| match entry.op {
+                journal::Operation::AddPackage => editor::add_package(content, &entry.value),
+                journal::Operation::SetOption => {
+                    editor::set_option(content, &entry.path, &entry.value)
+                }
+                journal::Operation::AddToList => {
+                    editor::add_to_list(content, &entry.path, &entry.value)
+                }
+            },
+            |state| match entry.op {
+                journal::Operation::AddPackage => state.add_package(&entry.value),
+                journal::Operation::SetOption => state.set_option(
+                    &entry.path,
+                    &managed_value(config, &entry.path, &entry.value, None),
+                ),
+                journal::Operation::AddToList => state.add_to_list(&entry.path, &entry.value),
+            },
+        );
+        if let Err(e) = result {
+            eprintln!("Failed to commit '{}': {}", entry.describe(), e);
+            return 1;
+        }
+    }
+    if let Err(e) = save_journal(config, &Journal::default()) {
+        eprintln!("Failed to remove temporary configuration: {}", e);
+        return 1;
+    }
+
+    println!(
+        "Committed temporary changes to the system configuration. {}",
+        switch_hint(config)
+    );
+    0
+}
+
+fn discard_temp_changes(config: &Config) -> i32 {
+    match load_journal(config) {
+        Ok(journal) if journal.is_empty() => {
+            println!("No temporary changes to discard.");
+            return 0;
+        }
+        Ok(_) => {}
+        Err(e) => {
+            eprintln!("Failed to read pending changes: {}", e);
+            return 1;
+        }
+    }
+
+    if let Err(e) = save_journal(config, &Journal::default()) {
+        eprintln!("Failed to remove temporary configuration: {}", e);
+        return 1;
+    }
+
+    println!("Discarded temporary changes.");
+    println!("Changes already applied stay active until the next reboot or rebuild.");
+    0
+}
+
+fn show_pending_changes(json: bool, config: &Config) -> i32 {
+    let journal = match load_journal(config) {
+        Ok(journal) => journal,
+        Err(e) => {
+            eprintln!("Failed to read pending changes: {}", e);
+            return 1;
+        }
+    };
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&journal.entries).unwrap_or_default()
+        );
+        return 0;
+    }
+
+    if journal.is_empty() {
+        println!("No pending changes.");
+        return 0;
+    }
+
+    println!("Pending changes in temporary configuration:");
+    for (i, entry) in journal.entries.iter().enumerate() {
+        println!(
+            "  {}. {} ({}, {})",
+            i + 1,
+            entry.describe(),
+            entry
+                .timestamp
+                .with_timezone(&chrono::Local)
+                .format("%Y-%m-%d %H:%M"),
+            entry.user
+        );
+    }
+    0
+}
+
+fn drop_pending_change(number: usize, config: &Config) -> i32 {
+    let mut journal = match load_journal(config) {
+        Ok(journal) => journal,
+        Err(e) => {
+            eprintln!("Failed to read pending changes: {}", e);
+            return 1;
+        }
+    };
+
+    let Some(entry) = journal.drop_entry(number) else {
+        eprintln!(
+            "No pending change #{}. Run 'nx sys show' to list them.",
+            number
+        );
+        return 1;
+    };
+    if let Err(e) = save_journal(config, &journal) {
+        eprintln!("Failed to modify temporary configuration: {}", e);
+        return 1;
+    }
+
+    println!("Dropped pending change #{}: {}", number, entry.describe());
+    0
+}
+
+fn export_configuration(prefix: Option<&str>, json: bool, pending: bool, config: &Config) -> i32 {
+    let temp_path = get_temp_path(config);
+    let overlay = if pending && temp_path.exists() {
+        Some(temp_path.as_path())
+    } else {
+        if pending {
+            eprintln!("No pending changes; exporting the current configuration.");
+        }
+        None
+    };
+    let prefix = prefix.map(|prefix| editor::join_path(&editor::split_path(prefix)));
+
+    let value = match eval::export_config(config, prefix.as_deref(), overlay) {
+        Ok(value) => value,
+        Err(e) => {
+            eprintln!("Failed to evaluate configuration: {}", e);
+            return 1;
+        }
+    };
+
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&value).unwrap_or_default()
+        );
+    } else {
+        let mut lines = Vec::new();
+        flatten_value(prefix.as_deref().unwrap_or_default(), &value, &mut lines);
+        for line in lines {
+            println!("{}", line);
+        }
+    }
+    0
+}
+
+/// Lists the leaves of an exported value as `path = value` lines.
+fn flatten_value(path: &str, value: &serde_json::Value, lines: &mut Vec<String>) {
+    match value {
+        serde_json::Value::Object(map) if !map.is_empty() => {
+            for (key, value) in map {
+                let name = editor::attr_name(key);
+                let path = if path.is_empty() {
+                    name
+                } else {
+                    format!("{}.{}", path, name)
+                };
+                flatten_value(&path, value, lines);
+            }
+        }
+        value => lines.push(format!("{} = {}", path, value)),
+    }
+}
+
+fn rebuild_system(mode: RebuildMode, extra_args: &[String], config: &Config) -> i32 {
+    match load_journal(config) {
+        Ok(journal) if !journal.is_empty() => println!(
+            "Note: {} pending temporary change(s) are not part of the configuration; \
+             run 'nx sys commit' first to include them.",
+            journal.entries.len()
+        ),
+        _ => {}
+    }
+
+    match nixos_rebuild(config, mode.action(), extra_args) {
+        Ok(status) if status.success() => {
+            println!("Rebuild ({}) finished.", mode.action());
+            0
+        }
+        Ok(status) => {
+            eprintln!("Rebuild ({}) failed with {}.", mode.action(), status);
+            status.code().unwrap_or(1)
+        }
+        Err(e) => {
+            eprintln!("Failed to run nixos-rebuild: {}", e);
+            1
+        }
+    }
+}
+
+fn show_history() -> i32 {
+    let generations = match generations::list() {
+        Ok(generations) => generations,
+        Err(e) => {
+            eprintln!("Failed to list system generations: {}", e);
+            return 1;
+        }
+    };
+    if generations.is_empty() {
+        println!("No system generations found.");
+        return 0;
+    }
+
+    println!(
+        "  {:>5}  {:<16}  {:<28}  Kernel",
+        "Gen", "Date", "NixOS version"
+    );
+    for generation in generations {
+        println!(
+            "{} {:>5}  {:<16}  {:<28}  {}",
+            if generation.current { "*" } else { " " },
+            generation.number,
+            generation
+                .created
+                .map(|created| created.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default(),
+            generation.nixos_version.as_deref().unwrap_or("unknown"),
+            generation.kernel.as_deref().unwrap_or("unknown")
+        );
+    }
+    0
+}
+
+fn rollback(generation: Option<u32>, config: &Config) -> i32 {
+    let status = match generation {
+        None => config
+            .privilege
+            .command("nixos-rebuild")
+            .args(["switch", "--rollback"])
+            .status(),
+        Some(number) => {
+            if !generations::link(number).exists() {
+                eprintln!(
+                    "No system generation {}. Run 'nx sys history' to list them.",
+                    number
+                );
+                return 1;
+            }
+            config
+                .privilege
+                .command("nix-env")
+                .args([
+                    "--profile",
+                    generations::SYSTEM_PROFILE,
+                    "--switch-generation",
+                ])
+                .arg(number.to_string())
+                .status()
+                .and_then(|status| {
+                    if !status.success() {
+                        return Ok(status);
+                    }
+                    config
+                        .privilege
+                        .command(&format!(
+                            "{}/bin/switch-to-configuration",
+                            generations::SYSTEM_PROFILE
+                        ))
+                        .arg("switch")
+                        .status()
+                })
+        }
+    };
+
+    match status {
+        Ok(status) if status.success() => {
+            match generation {
+                Some(number) => println!("Switched to generation {}.", number),
+                None => println!("Switched to the previous generation."),
+            }
+            0
+        }
+        Ok(status) => {
+            eprintln!("Rollback failed with {}.", status);
+            1
+        }
+        Err(e) => {
+            eprintln!("Failed to roll back: {}", e);
+            1
+        }
+    }
+}
+
+fn show_generation_diff(from: u32, to: u32) -> i32 {
+    for number in [from, to] {
+        if !generations::link(number).exists() {
+            eprintln!(
+                "No system generation {}. Run 'nx sys history' to list them.",
+                number
+            );
+            return 1;
+        }
+    }
+    let changes = match generations::diff_closures(&generations::link(from), &generations::link(to))
+    {
+        Ok(changes) => changes,
+        Err(e) => {
+            eprintln!("Failed to compare generations: {}", e);
+            return 1;
+        }
+    };
+    if changes.is_empty() {
+        println!("Generations {} and {} have the same packages.", from, to);
+        return 0;
+    }
+
+    for (kind, title) in [
+        (generations::ChangeKind::Added, "Added"),
+        (generations::ChangeKind::Removed, "Removed"),
+        (generations::ChangeKind::Changed, "Upgraded or changed"),
+    ] {
+        let section: Vec<_> = changes
+            .iter()
+            .filter(|change| change.kind == kind)
+            .collect();
+        if section.is_empty() {
+            continue;
+        }
+        println!("{}:", title);
+        for change in section {
+            let mut line = format!("  {}", change.name);
+            if kind == generations::ChangeKind::Changed {
+                if let Some(versions) = &change.versions {
+                    line.push_str(&format!(" {}", versions));
+                }
+            }
+            if let Some(size) = change.size_kib {
+                line.push_str(&format!(" ({})", format_size_delta(size)));
+            }
+            println!("{}", line);
+        }
+    }
+
+    let total: f64 = changes.iter().filter_map(|change| change.size_kib).sum();
+    println!("Closure size change: {}", format_size_delta(total));
+    0
+}
+
+/// Formats a size change given in KiB, e.g. `+1.5 MiB`.
+fn format_size_delta(kib: f64) -> String {
+    let sign = if kib < 0.0 { "-" } else { "+" };
+    let kib = kib.abs();
+    if kib >= 1024.0 * 1024.0 {
+        format!("{}{:.1} GiB", sign, kib / 1024.0 / 1024.0)
+    } else if kib >= 1024.0 {
+        format!("{}{:.1} MiB", sign, kib / 1024.0)
+    } else {
+        format!("{}{:.1} KiB", sign, kib)
+    }
+}
+
+/// What to do with an edit that failed to check.
+enum EditChoice {
+    Edit,
+    Keep,
+    Restore,
+}
+
+fn edit_configuration(file: Option<&Path>, config: &Config) -> i32 {
+    let path = file
+        .map(|file| config.nixos_root.join(file))
+        .unwrap_or_else(|| get_config_path(config));
+    let original = match fs::read_to_string(&path) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("Failed to read {}: {}", path.display(), e);
+            return 1;
+        }
+    };
+
+    // Edit a private copy, which the user can write even when the
+    // configuration itself needs root. It lives in a directory only we can
+    // access, as the configuration may hold secrets.
+    let dir = match crate::utils::file::private_temp_dir("nx-edit-") {
+        Ok(dir) => dir,
+        Err(e) => {
+            eprintln!("Failed to create a temporary directory: {}", e);
+            return 1;
+        }
+    };
+    let edit_path = dir.join(path.file_name().unwrap_or("configuration.nix".as_ref()));
+    if let Err(e) = write_private(&edit_path, &original) {
+        eprintln!("Failed to create {}: {}", edit_path.display(), e);
+        let _ = fs::remove_dir_all(&dir);
+        return 1;
+    }
+
+    let result = edit_loop(&path, &edit_path, &original, config);
+    let _ = fs::remove_dir_all(&dir);
+    result
+}
+
+/// Writes `content` to a new file at `path` readable only by the current user.
+fn write_private(path: &Path, content: &str) -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+    fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(content.as_bytes())
+}
+
+fn edit_loop(path: &Path, edit_path: &Path, original: &str, config: &Config) -> i32 {
+    let mut installed = false;
+    loop {
+        if let Err(e) = run_editor(edit_path) {
+            eprintln!("Failed to run editor: {}", e);
+            return 1;
+        }
+        let edited = match fs::read_to_string(edit_path) {
+            Ok(content) => content,
+            Err(e) => {
+                eprintln!("Failed to read {}: {}", edit_path.display(), e);
+                return 1;
+            }
+        };
+        if edited == original && !installed {
+            println!("No changes made to {}.", path.display());
+            return 0;
+        }
+
+        // A file that does not parse is not installed unless the user insists;
+        // one that parses is installed so the whole system can be evaluated
+        let problem = match editor::parse(&edited) {
+            Err(e) => Some(e.to_string()),
+            Ok(_) => match replace_config_file(config, path, &edited) {
+                Err(e) => {
+                    eprintln!("Failed to write {}: {}", path.display(), e);
+                    return 1;
+                }
+                Ok(()) => {
+                    installed = true;
+                    println!("Checking the configuration...");
+                    eval::check_system(config).err().map(|e| e.to_string())
+                }
+            },
+        };
+
+        let Some(problem) = problem else {
+            println!("Updated {}. {}", path.display(), switch_hint(config));
+            return 0;
+        };
+        eprintln!("The edited configuration has errors:\n{}", problem);
+
+        match prompt_edit_choice() {
+            EditChoice::Edit => continue,
+            EditChoice::Keep => {
+                if let Err(e) = replace_config_file(config, path, &edited) {
+                    eprintln!("Failed to write {}: {}", path.display(), e);
+                    return 1;
+                }
+                println!("Kept the edited {}.", path.display());
+                return 0;
+            }
+            EditChoice::Restore => {
+                if installed {
+                    if let Err(e) = replace_config_file(config, path, original) {
+                        eprintln!("Failed to restore {}: {}", path.display(), e);
+                        return 1;
+                    }
+                }
+                println!("Restored the original {}.", path.display());
+                return 1;
+            }
+        }
+    }
+}
+
+/// Opens `path` in $VISUAL or $EDITOR (nano when neither is set).
+fn run_editor(path: &Path) -> std::io::Result<()> {
+    let editor = std::env::var("VISUAL")
+        .or_else(|_| std::env::var("EDITOR"))
+        .unwrap_or_else(|_| "nano".to_string());
+    let mut words = editor.split_whitespace();
+    let program = words.next().unwrap_or("nano");
+    let status = Command::new(program).args(words).arg(path).status()?;
+    if !status.success() {
+        return Err(std::io::Error::other(format!(
+            "{} exited with {}",
+            program, status
+        )));
+    }
+    Ok(())
+}
+
+/// Asks what to do with a failed edit; without a terminal the original is
+/// restored.
+fn prompt_edit_choice() -> EditChoice {
+    const EDIT: &str = "Edit again";
+    const KEEP: &str = "Keep anyway";
+    const RESTORE: &str = "Restore the original";
+    match inquire::Select::new("What now?", vec![EDIT, KEEP, RESTORE]).prompt() {
+        Ok(EDIT) => EditChoice::Edit,
+        Ok(KEEP) => EditChoice::Keep,
+        _ => EditChoice::Restore,
+    }
+}
+
+/// Replaces a configuration file's content, keeping a backup like other edits.
+pub fn replace_config_file(config: &Config, path: &Path, content: &str) -> std::io::Result<()> {
+    modify_config_file(config, path, |_| Ok((content.to_string(), Change::Updated)))?;
+    Ok(())
+}
+
+fn show_diff(eval: bool, config: &Config) -> i32 {
+    match print_diff(eval, config) {
+        Ok(true) => 0,
+        Ok(false) => {
+            println!("No changes.");
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to compare configuration: {}", e);
+            1
+        }
+    }
+}
+
+/// Prints unified diffs of the configuration files against the last rebuild
+/// (or, before nx has rebuilt, their oldest backups) and of the pending
+/// overlay, followed by the options that change when `eval` is set. Returns
+/// whether anything differs.
+fn print_diff(eval: bool, config: &Config) -> std::io::Result<bool> {
+    let store = get_backup_store(config);
+    let rebuilt = store.rebuilt()?;
+    let backups = store.list()?;
+    let mut files = modules::module_files(&get_config_path(config));
+    match &rebuilt {
+        Some(state) => {
+            // Also catch modules that are no longer imported
+            for file in &state.files {
+                if !files.contains(file) {
+                    files.push(file.clone());
+                }
+            }
+        }
+        None => println!("No rebuild recorded by nx yet; comparing with the oldest backups.\n"),
+    }
+
+    let mut changed = false;
+    let mut option_changes = Vec::new();
+    for file in files {
+        let (baseline, label) = match &rebuilt {
+            Some(state) => (
+                store.read_rebuilt(state, &file)?.unwrap_or_default(),
+                format!("{} (last rebuild)", file.display()),
+            ),
+            None => match backups.iter().find(|backup| backup.original == file) {
+                Some(backup) => (
+                    store.read(backup)?,
+                    format!("{} (backup {})", file.display(), backup.id),
+                ),
+                None => continue,
+            },
+        };
+        let current = if file.exists() {
+            fs::read_to_string(&file)?
+        } else {
+            String::new()
+        };
+
+        let text = diff::unified(&baseline, &current, &label, &file.display().to_string());
+        if !text.is_empty() {
+            print!("{}", text);
+            changed = true;
+            option_changes.extend(diff::changed_options(&baseline, &current));
+        }
+    }
+
+    let journal = load_journal(config)?;
+    let temp_path = get_temp_path(config);
+    if !journal.is_empty() && temp_path.exists() {
+        let config_path = get_config_path(config);
+        let base = (!config.is_flake()).then_some(config_path.as_path());
+        let empty = Journal::default().render(base)?;
+        let pending = fs::read_to_string(&temp_path)?;
+        print!(
+            "{}",
+            diff::unified(
+                &empty,
+                &pending,
+                &format!("{} (no pending changes)", temp_path.display()),
+                &temp_path.display().to_string()
+            )
+        );
+        changed = true;
+    }
+
+    if !eval || !changed {
+        return Ok(changed);
+    }
+
+    println!("\nOption changes:");
+    for change in option_changes {
+        println!(
+            "  {}: {} -> {}",
+            change.path,
+            change.old.as_deref().unwrap_or("(unset)"),
+            change.new.as_deref().unwrap_or("(unset)")
+        );
+    }
+    let mut paths: Vec<&str> = Vec::new();
+    for entry in &journal.entries {
+        if !paths.contains(&entry.path.as_str()) {
+            paths.push(&entry.path);
+        }
+    }
+    for path in paths {
+        let before = eval::export_config(config, Some(path), None);
+        let after = eval::export_config(config, Some(path), Some(&temp_path));
+        match (before, after) {
+            (Ok(before), Ok(after)) if before == after => {}
+            (Ok(before), Ok(after)) => println!("  {}: {} -> {} (pending)", path, before, after),
+            (Err(e), _) | (_, Err(e)) => println!("  {}: failed to evaluate: {}", path, e),
+        }
+    }
+    Ok(changed)
+}
+
+/// The newest backup of `path` whose content has the given hash.
+pub fn find_backup(config: &Config, path: &Path, hash: &str) -> std::io::Result<Option<String>> {
+    let store = get_backup_store(config);
+    for backup in store.list()?.iter().rev() {
+        if backup.original == path {
+            let content = store.read(backup)?;
+            if audit::content_hash(content.as_bytes()) == hash {
+                return Ok(Some(content));
+            }
+        }
+    }
+    Ok(None)
+}
+
+/// The system generation currently active.
+pub fn current_generation() -> Option<u32> {
+    generations::current()
+}
+
+fn list_backups(config: &Config) -> i32 {
+    match get_backup_store(config).list() {
+        Ok(backups) if backups.is_empty() => {
+            println!("No configuration backups.");
+            0
+        }
+        Ok(backups) => {
+            println!("Configuration backups (newest last):");
+            for backup in backups {
+                println!(
+                    "  {}  {}  {}",
+                    backup.id,
+                    backup
+                        .created
+                        .with_timezone(&chrono::Local)
+                        .format("%Y-%m-%d %H:%M:%S"),
+                    backup.original.display()
+                );
+            }
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to read backups: {}", e);
+            1
+        }
+    }
+}
+
+fn restore_backup(id: &str, config: &Config) -> i32 {
+    let store = get_backup_store(config);
+    let backup = match store.find(id) {
+        Ok(Some(backup)) => backup,
+        Ok(None) => {
+            eprintln!(
+                "No backup with ID {}. Run 'nx sys backups' to list them.",
+                id
+            );
+            return 1;
+        }
+        Err(e) => {
+            eprintln!("Failed to read backups: {}", e);
+            return 1;
+        }
+    };
+
+    let content = match store.read(&backup) {
+        Ok(content) => content,
+        Err(e) => {
+            eprintln!("Failed to read backup {}: {}", id, e);
+            return 1;
+        }
+    };
+
+    // The current file is itself backed up, so a restore can be undone
+    if let Err(e) = modify_config_file(config, &backup.original, |_| Ok((content, Change::Updated)))
+    {
+        eprintln!("Failed to restore {}: {}", backup.original.display(), e);
+        return 1;
+    }
+
+    println!(
+        "Restored {} from backup {}. {}",
+        backup.original.display(),
+        id,
+        switch_hint(config)
+    );
+    0
+}
+
+/// The files making up the configuration: its modules, the nx-managed
+/// module's state and the flake files.
+pub fn get_configuration_files(config: &Config) -> Vec<PathBuf> {
+    let mut files = modules::module_files(&get_config_path(config));
+    let extra = [
+        get_managed_state_path(config),
+        config.nixos_root.join("flake.nix"),
+        config.nixos_root.join("flake.lock"),
+    ];
+    for path in extra {
+        if path.exists() && !files.contains(&path) {
+            files.push(path);
+        }
+    }
+    files
+}
+
+/// Everything in the configuration root except nx's scratch files and the
+/// git repository, plus any module kept outside of it.
+fn get_snapshot_files(config: &Config) -> std::io::Result<Vec<PathBuf>> {
+    let mut exclude = PRIVATE_FILES.to_vec();
+    exclude.push(".git");
+    let mut files = modules::directory_files(&config.nixos_root, &exclude)?;
+    for path in get_configuration_files(config) {
+        if !files.contains(&path) {
+            files.push(path);
+        }
+    }
+    Ok(files)
+}
+
+fn create_snapshot(label: &str, force: bool, config: &Config) -> i32 {
+    if !snapshot::valid_label(label) {
+        eprintln!(
+            "Invalid snapshot label '{}': it must not be empty, start with '.' or contain '/'.",
+            label
+        );
+        return 1;
+    }
+    let store = get_snapshot_store(config);
+    match store.find(label) {
+        Ok(Some(_)) if !force => {
+            eprintln!(
+                "Snapshot '{}' already exists. Use --force to replace it.",
+                label
+            );
+            return 1;
+        }
+        Err(e) => {
+            eprintln!("Failed to read snapshots: {}", e);
+            return 1;
+        }
+        _ => {}
+    }
+
+    let files = match get_snapshot_files(config).and_then(|files| {
+        files
+            .into_iter()
+            .map(|file| config.privilege.read(&file).map(|content| (file, content)))
+            .collect::<std::io::Result<Vec<_>>>()
+    }) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("Failed to read the configuration: {}", e);
+            return 1;
+        }
+    };
+
+    match store.create(label, generations::current(), &files) {
+        Ok(snapshot) => {
+            print!(
+                "Saved {} file(s) as snapshot '{}'",
+                snapshot.files.len(),
+                label
+            );
+            match snapshot.generation {
+                Some(generation) => println!(" at generation {}.", generation),
+                None => println!("."),
+            }
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to save snapshot '{}': {}", label, e);
+            1
+        }
+    }
+}
+
+fn list_snapshots(config: &Config) -> i32 {
+    match get_snapshot_store(config).list() {
+        Ok(snapshots) if snapshots.is_empty() => {
+            println!("No configuration snapshots.");
+            0
+        }
+        Ok(snapshots) => {
+            println!("Configuration snapshots (newest last):");
+            for snapshot in snapshots {
+                println!(
+                    "  {}  {}  {} file(s){}",
+                    snapshot.label,
+                    snapshot
+                        .created
+                        .with_timezone(&chrono::Local)
+                        .format("%Y-%m-%d %H:%M:%S"),
+                    snapshot.files.len(),
+                    snapshot
+                        .generation
+                        .map(|generation| format!(", generation {}", generation))
+                        .unwrap_or_default()
+                );
+            }
+            0
+        }
+        Err(e) => {
+            eprintln!("Failed to read snapshots: {}", e);
+            1
+        }
+    }
+}
+
+fn restore_snapshot(label: &str, rebuild: bool, config: &Config) -> i32 {
+    let store = get_snapshot_store(config);
+    let snapshot = match store.find(label) {
+        Ok(Some(snapshot)) => snapshot,
+        Ok(None) => {
+            eprintln!(
+                "No snapshot '{}'. Run 'nx sys snapshots' to list them.",
+                label
+            );
+            return 1;
+        }
+        Err(e) => {
+            eprintln!("Failed to read snapshots: {}", e);
+            return 1;
+        }
+    };
+    let files = match store.read(&snapshot) {
+        Ok(files) => files,
+        Err(e) => {
+            eprintln!("Failed to read snapshot '{}': {}", label, e);
+            return 1;
+        }
+    };
+
+    let mut restored = 0;
+    for (path, content) in files {
+        // Existing text files are backed up, so a restore can be undone file
+        // by file; other files are only rewritten when they differ
+        let result = if path.exists() && fs::read_to_string(&path).is_ok() {
+            match String::from_utf8(content) {
+                Ok(content) => modify_config_file(config, &path, |current| {
+                    let change = if current == content {
+                        Change::Unchanged
+                    } else {
+                        Change::Updated
+                    };
+                    Ok((content, change))
+                }),
+                Err(e) => config
+                    .privilege
+                    .write(&path, e.as_bytes())
+                    .map(|()| Change::Updated),
+            }
+        } else if config
+            .privilege
+            .read(&path)
+            .is_ok_and(|current| current == content)
+        {
+            Ok(Change::Unchanged)
+        } else {
+            path.parent()
+                .map_or(Ok(()), |dir| config.privilege.create_dir_all(dir))
+                .and_then(|()| config.privilege.write(&path, &content))
+                .map(|()| Change::Updated)
+        };
+        match result {
+            Ok(Change::Unchanged) => {}
+            Ok(_) => {
+                println!("Restored {}", path.display());
+                restored += 1;
+            }
+            Err(e) => {
+                eprintln!("Failed to restore {}: {}", path.display(), e);
+                return 1;
+            }
+        }
+    }
+
+    if restored == 0 {
+        println!("The configuration already matches snapshot '{}'.", label);
+    } else {
+        println!("Restored {} file(s) from snapshot '{}'.", restored, label);
+    }
+    // With --rebuild, execute rebuilds after committing the restored files
+    if !rebuild {
+        println!("{}", switch_hint(config));
+    }
+    0
+}
+
+/// Applies `modifier` to the file at `path`, writing it back only if the
+/// content changed.
+///
+/// The previous content is kept in the backup directory and the new content
+/// is written atomically, so a failure never leaves a truncated file behind.
+pub fn modify_config_file<F>(config: &Config, path: &Path, modifier: F) -> std::io::Result<Change>
+where
+    F: FnOnce(&str) -> Result<(String, Change), EditError>,
+{
+    let content = fs::read_to_string(path)?;
+    let (new_content, change) = modifier(&content)?;
+    if new_content != content {
+        get_backup_store(config).create(path, &content, config.backup_retention)?;
+        config.privilege.write(path, new_content.as_bytes())?;
+    }
+    Ok(change)
+}
@@ -0,0 +1,155 @@
+//! Discovery of the module files making up a NixOS configuration.
+
+use super::editor;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Generated by `nixos-generate-config`, which overwrites it.
+const HARDWARE_CONFIG_FILE: &str = "hardware-configuration.nix";
+
+/// Returns `root` and every module it imports, transitively, in depth-first
+/// order. Imports that are not local paths (e.g. `<nixos-hardware/...>`) or
+/// that cannot be read are skipped.
+pub fn module_files(root: &Path) -> Vec<PathBuf> {
+    let mut files = Vec::new();
+    visit(root, &mut files);
+    files
+}
+
+fn visit(path: &Path, files: &mut Vec<PathBuf>) {
+    let path = if path.is_dir() {
+        path.join("default.nix")
+    } else {
+        path.to_path_buf()
+    };
+    if files.contains(&path) || !path.is_file() {
+        return;
+    }
+    files.push(path.clone());
+
+    let Ok(content) = fs::read_to_string(&path) else {
+        return;
+    };
+    let Ok(imports) = editor::import_paths(&content) else {
+        return;
+    };
+    let dir = path.parent().unwrap_or(Path::new("/"));
+    for import in imports {
+        visit(&resolve(dir, &import), files);
+    }
+}
+
+fn resolve(dir: &Path, import: &str) -> PathBuf {
+    if Path::new(import).is_absolute() {
+        PathBuf::from(import)
+    } else {
+        dir.join(import.trim_start_matches("./"))
+    }
+}
+
+/// Lists the regular files below `root`, recursively and sorted. Entries
+/// named in `exclude` are skipped at any depth, and symlinks (such as the
+/// `result` links `nixos-rebuild build` leaves behind) are not followed.
+pub fn directory_files(root: &Path, exclude: &[&str]) -> io::Result<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let mut entries: Vec<_> = fs::read_dir(root)?.collect::<io::Result<_>>()?;
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        if exclude.iter().any(|name| entry.file_name() == *name) {
+            continue;
+        }
+        let file_type = entry.file_type()?;
+        if file_type.is_dir() {
+            files.extend(directory_files(&entry.path(), exclude)?);
+        } else if file_type.is_file() {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+/// Picks the file that already defines `option`, or an attribute set above
+/// it, preferring the closest definition; ties go to the earlier file. Files
+/// that only share unrelated settings under the same prefix do not count,
+/// and `hardware-configuration.nix`, which `nixos-generate-config`
+/// overwrites, is never picked. Returns `None` when no file defines it.
+pub fn definition_file(files: &[PathBuf], option: &str) -> Option<PathBuf> {
+    let mut best: Option<(usize, &PathBuf)> = None;
+    for file in files {
+        if file
+            .file_name()
+            .is_some_and(|name| name == HARDWARE_CONFIG_FILE)
+        {
+            continue;
+        }
+        let depth = fs::read_to_string(file)
+            .ok()
+            .and_then(|content| editor::definition_depth(&content, option).ok())
+            .unwrap_or(0);
+        if depth > 0 && best.is_none_or(|(best_depth, _)| depth > best_depth) {
+            best = Some((depth, file));
+        }
+    }
+    best.map(|(_, file)| file.clone())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn edits_go_where_the_option_is_defined() {
+        let dir = std::env::temp_dir().join(format!("nx-modules-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let configuration = dir.join("configuration.nix");
+        let hardware = dir.join(HARDWARE_CONFIG_FILE);
+        let services = dir.join("services.nix");
+        fs::write(
+            &configuration,
+            "{ ... }:\n{\n  imports = [ ./hardware-configuration.nix ./services.nix ];\n}\n",
+        )
+        .unwrap();
+        fs::write(
+            &hardware,
+            "{ ... }:\n{\n  boot.initrd.availableKernelModules = [ \"ahci\" ];\n  \
+             hardware.cpu.intel.updateMicrocode = true;\n  nixpkgs.hostPlatform = \"x86_64-linux\";\n  \
+             networking.useDHCP = true;\n}\n",
+        )
+        .unwrap();
+        fs::write(
+            &services,
+            "{ ... }:\n{\n  services.nginx = {\n    enable = true;\n  };\n  \
+             services.openssh.enable = true;\n}\n",
+        )
+        .unwrap();
+
+        let files = module_files(&configuration);
+        assert_eq!(
+            files,
+            vec![configuration.clone(), hardware.clone(), services.clone()]
+        );
+        for option in [
+            "boot.loader.systemd-boot.enable",
+            "hardware.bluetooth.enable",
+            "nixpkgs.config.allowUnfree",
+            "networking.useDHCP",
+            "services.printing.enable",
+        ] {
+            assert_eq!(definition_file(&files, option), None, "{}", option);
+        }
+        for option in [
+            "services.nginx.virtualHosts.example.root",
+            "services.openssh.enable",
+            "services.openssh",
+        ] {
+            assert_eq!(
+                definition_file(&files, option),
+                Some(services.clone()),
+                "{}",
+                option
+            );
+        }
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
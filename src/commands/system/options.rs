@@ -0,0 +1,371 @@
+//! The NixOS options database and conversion of command-line input to the
+//! Nix expression an option's declared type expects.
+
+use super::editor::{list_element, quote, split_path};
+use super::settings::number_to_nix;
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Number of "did you mean" suggestions offered for an unknown option.
+const MAX_SUGGESTIONS: usize = 3;
+
+/// An option declaration from `options.json`; fields we do not use are
+/// ignored.
+#[derive(Deserialize)]
+pub struct OptionInfo {
+    #[serde(rename = "type")]
+    pub ty: String,
+}
+
+pub struct OptionsDb {
+    options: HashMap<String, OptionInfo>,
+}
+
+/// The result of looking up an option path.
+pub enum Lookup<'a> {
+    /// The path names a declared option
+    Option(&'a OptionInfo),
+    /// The path is a prefix of declared options, e.g. `services.openssh`
+    Prefix,
+    /// The path is below a declared attribute set or submodule option, e.g.
+    /// `environment.variables.EDITOR`; holds the type of the value there when
+    /// it is known
+    Nested(Option<String>),
+    /// Nothing is declared at the path; holds the closest option names
+    Unknown(Vec<String>),
+}
+
+impl OptionsDb {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let content = fs::read(path)?;
+        let options = serde_json::from_slice(&content)?;
+        Ok(OptionsDb { options })
+    }
+
+    pub fn lookup(&self, path: &str) -> Lookup<'_> {
+        if let Some(info) = self.options.get(path) {
+            return Lookup::Option(info);
+        }
+
+        let segments = split_path(path);
+        let mut prefix = false;
+        // The longest declared option above the path, with its length
+        let mut parent: Option<(usize, &OptionInfo)> = None;
+        for (name, info) in &self.options {
+            let declared = split_path(name);
+            if !matches(&declared, &segments) {
+                continue;
+            }
+            if declared.len() == segments.len() {
+                return Lookup::Option(info);
+            }
+            if declared.len() > segments.len() {
+                prefix = true;
+            } else if parent.is_none_or(|(len, _)| declared.len() > len) {
+                parent = Some((declared.len(), info));
+            }
+        }
+        if prefix {
+            return Lookup::Prefix;
+        }
+        if let Some((len, info)) = parent {
+            if let Some(ty) = nested_type(&info.ty, segments.len() - len) {
+                return Lookup::Nested(ty);
+            }
+        }
+
+        let mut scored: Vec<(usize, &String)> = self
+            .options
+            .keys()
+            .map(|name| (edit_distance(path, name), name))
+            .filter(|(distance, _)| *distance <= (path.len() / 4).max(2))
+            .collect();
+        scored.sort();
+        Lookup::Unknown(
+            scored
+                .into_iter()
+                .take(MAX_SUGGESTIONS)
+                .map(|(_, name)| name.clone())
+                .collect(),
+        )
+    }
+}
+
+/// Whether the leading segments of a declared option name match `path`,
+/// where `<name>` and `*` placeholders match any segment.
+fn matches(declared: &[String], path: &[String]) -> bool {
+    declared.iter().zip(path).all(|(declared, segment)| {
+        declared == segment || *declared == "*" || is_placeholder(declared)
+    })
+}
+
+/// For an option of type `ty`, the type of the value `depth` attribute names
+/// below it: `Some(Some(ty))` for attribute sets of a known element type,
+/// `Some(None)` for submodules and free-form values whose keys we cannot
+/// check, and `None` if the option has no attributes.
+fn nested_type(ty: &str, depth: usize) -> Option<Option<String>> {
+    let ty = strip_parens(ty);
+    if let Some(inner) = ty.strip_prefix("null or ") {
+        return nested_type(inner, depth);
+    }
+    if let Some(inner) = ty.strip_prefix("open submodule of ") {
+        return Some(nested_type(inner, depth).flatten());
+    }
+    if let Some(inner) = ty
+        .strip_prefix("attribute set of ")
+        .or_else(|| ty.strip_prefix("lazy attribute set of "))
+    {
+        let inner = strip_parens(inner);
+        if depth == 1 {
+            return Some(Some(inner.to_string()));
+        }
+        return Some(nested_type(inner, depth - 1).flatten());
+    }
+    if ty.contains("submodule") || ty.ends_with(" value") {
+        return Some(None);
+    }
+    None
+}
+
+/// Removes parentheses around a whole type description, as in
+/// `(submodule)`.
+fn strip_parens(ty: &str) -> &str {
+    let Some(inner) = ty.strip_prefix('(').and_then(|rest| rest.strip_suffix(')')) else {
+        return ty;
+    };
+    // `(a) or (b)` starts and ends with parentheses that do not match
+    let mut depth = 0;
+    for c in inner.chars() {
+        match c {
+            '(' => depth += 1,
+            ')' if depth == 0 => return ty,
+            ')' => depth -= 1,
+            _ => {}
+        }
+    }
+    strip_parens(inner)
+}
+
+fn is_placeholder(segment: &str) -> bool {
+    segment.starts_with('<') && segment.ends_with('>')
+}
+
+/// Converts command-line input to a Nix expression of the option type
+/// described by `ty` (the type description from `options.json`). Input that
+/// already is an expression of the right shape is kept as it is, and types we
+/// do not know how to convert pass the input through unchanged.
+pub fn coerce(ty: &str, input: &str) -> Result<String, String> {
+    let ty = strip_parens(ty);
+    let input = input.trim();
+    if let Some(inner) = ty.strip_prefix("null or ") {
+        if input == "null" {
+            return Ok(input.to_string());
+        }
+        return coerce(inner, input);
+    }
+    if let Some(inner) = ty.strip_prefix("list of ") {
+        if input.starts_with('[') {
+            return Ok(input.to_string());
+        }
+        let items = input
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| coerce(inner, item).and_then(|item| list_item(&item)))
+            .collect::<Result<Vec<_>, _>>()?;
+        if items.is_empty() {
+            return Ok("[ ]".to_string());
+        }
+        return Ok(format!("[ {} ]", items.join(" ")));
+    }
+    if let Some(choices) = ty.strip_prefix("one of ") {
+        return coerce_choice(choices, input);
+    }
+
+    match ty {
+        "boolean" => match input.to_ascii_lowercase().as_str() {
+            "true" | "yes" | "on" | "1" => Ok("true".to_string()),
+            "false" | "no" | "off" | "0" => Ok("false".to_string()),
+            _ => Err(format!(
+                "expected a boolean (true/false or yes/no), got '{}'",
+                input
+            )),
+        },
+        // Nix floats need a decimal point and have no infinities
+        "floating point number" => input
+            .parse::<f64>()
+            .ok()
+            .and_then(serde_json::Number::from_f64)
+            .map(|number| number_to_nix(&number))
+            .ok_or_else(|| format!("expected a number, got '{}'", input)),
+        "package" => {
+            if is_attr_name(input) && !input.starts_with("pkgs.") {
+                Ok(format!("pkgs.{}", input))
+            } else {
+                Ok(input.to_string())
+            }
+        }
+        _ if is_string_type(ty) => {
+            if is_quoted(input) {
+                Ok(input.to_string())
+            } else {
+                Ok(quote(input))
+            }
+        }
+        _ if ty.contains("integer") => input
+            .parse::<i64>()
+            .map(|_| input.to_string())
+            .map_err(|_| format!("expected an integer, got '{}'", input)),
+        _ => Ok(input.to_string()),
+    }
+}
+
+/// Parenthesizes an item where a list needs it, e.g. a negative number.
+fn list_item(item: &str) -> Result<String, String> {
+    list_element(item).map_err(|e| e.to_string())
+}
+
+/// Converts input for an enum type, whose choices are listed like
+/// `"a", "b", "c"`.
+fn coerce_choice(choices: &str, input: &str) -> Result<String, String> {
+    let choices: Vec<&str> = choices.split(", ").collect();
+    let quoted = quote(input.trim_matches('"'));
+    if choices.contains(&input) {
+        Ok(input.to_string())
+    } else if choices.contains(&quoted.as_str()) {
+        Ok(quoted)
+    } else {
+        Err(format!(
+            "expected one of {}, got '{}'",
+            choices.join(", "),
+            input
+        ))
+    }
+}
+
+fn is_string_type(ty: &str) -> bool {
+    ty.starts_with("string")
+        || ty.starts_with("non-empty string")
+        || ty.starts_with("single-line string")
+        || ty == "path"
+        || ty == "absolute path"
+}
+
+fn is_quoted(input: &str) -> bool {
+    (input.len() >= 2 && input.starts_with('"') && input.ends_with('"'))
+        || (input.len() >= 4 && input.starts_with("''") && input.ends_with("''"))
+}
+
+fn is_attr_name(input: &str) -> bool {
+    !input.is_empty()
+        && input
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-' | '\'' | '.'))
+}
+
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut previous = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let current = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                previous
+            } else {
+                previous.min(current).min(row[j]) + 1
+            };
+            previous = current;
+        }
+    }
+    row[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::editor::check_value;
+    use super::*;
+
+    fn db(options: &[(&str, &str)]) -> OptionsDb {
+        OptionsDb {
+            options: options
+                .iter()
+                .map(|(name, ty)| (name.to_string(), OptionInfo { ty: ty.to_string() }))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn paths_below_attribute_sets_use_the_element_type() {
+        let db = db(&[(
+            "environment.variables",
+            "attribute set of (null or (string or absolute path or signed integer))",
+        )]);
+        let Lookup::Nested(Some(ty)) = db.lookup("environment.variables.EDITOR") else {
+            panic!("expected a nested lookup");
+        };
+        assert_eq!(coerce(&ty, "vim").unwrap(), "\"vim\"");
+    }
+
+    #[test]
+    fn quoted_segments_below_free_form_options_are_accepted() {
+        let db = db(&[
+            (
+                "boot.kernel.sysctl",
+                "open submodule of attribute set of (sysctl option value)",
+            ),
+            (
+                "boot.kernel.sysctl.\"net.core.rmem_max\"",
+                "null or (unsigned integer)",
+            ),
+            ("services.grafana.settings", "open submodule of (INI value)"),
+        ]);
+        assert!(matches!(
+            db.lookup("boot.kernel.sysctl.\"net.ipv4.ip_forward\""),
+            Lookup::Nested(_)
+        ));
+        assert!(matches!(
+            db.lookup("boot.kernel.sysctl.\"net.core.rmem_max\""),
+            Lookup::Option(_)
+        ));
+        assert!(matches!(
+            db.lookup("services.grafana.settings.server.http_port"),
+            Lookup::Nested(None)
+        ));
+    }
+
+    #[test]
+    fn paths_below_plain_options_are_unknown() {
+        let db = db(&[("networking.hostName", "string")]);
+        assert!(matches!(
+            db.lookup("networking.hostName.foo"),
+            Lookup::Unknown(_)
+        ));
+    }
+
+    #[test]
+    fn negative_list_items_are_parenthesized() {
+        let value = coerce("list of signed integer", "-1,2").unwrap();
+        assert_eq!(value, "[ (-1) 2 ]");
+        check_value(&value).unwrap();
+        assert_eq!(
+            coerce("list of floating point number", "0.5, -1e-3").unwrap(),
+            "[ 0.5 (-0.001) ]"
+        );
+    }
+
+    #[test]
+    fn floats_are_written_as_nix_floats() {
+        assert_eq!(coerce("floating point number", "1e5").unwrap(), "100000.0");
+        assert_eq!(coerce("floating point number", "2").unwrap(), "2.0");
+        assert_eq!(coerce("floating point number", "1.5e-9").unwrap(), "1.5e-9");
+        assert_eq!(coerce("floating point number", "-0.25").unwrap(), "(-0.25)");
+        for input in ["inf", "-infinity", "NaN", "abc"] {
+            assert!(coerce("floating point number", input).is_err(), "{}", input);
+        }
+    }
+}
@@ -0,0 +1,246 @@
+//! Compact progress display for nix commands run with
+//! `--log-format internal-json`.
+//!
+//! nix writes one `@nix {...}` JSON event per line to stderr, describing
+//! activities (builds, downloads) as they start, report progress and stop.
+//! Everything else on stderr, such as nixos-rebuild's own messages and the
+//! activation script's output, is passed through as it is.
+
+use serde::Deserialize;
+use std::collections::HashMap;
+use std::io::{self, BufRead, BufReader, Write};
+use std::process::{Command, ExitStatus, Stdio};
+
+const EVENT_PREFIX: &str = "@nix ";
+/// Width of the progress line when the terminal size is unknown.
+const DEFAULT_WIDTH: usize = 100;
+/// Messages up to this verbosity (0 error, 1 warning, 2 notice) are shown.
+const MAX_MESSAGE_LEVEL: u64 = 2;
+
+// Activity types
+const ACT_FILE_TRANSFER: u64 = 101;
+const ACT_COPY_PATHS: u64 = 103;
+const ACT_BUILDS: u64 = 104;
+const ACT_BUILD: u64 = 105;
+
+// Result types
+const RES_BUILD_LOG_LINE: u64 = 101;
+const RES_SET_PHASE: u64 = 104;
+const RES_PROGRESS: u64 = 105;
+
+#[derive(Deserialize)]
+struct Event {
+    action: String,
+    #[serde(default)]
+    id: u64,
+    #[serde(rename = "type", default)]
+    kind: u64,
+    #[serde(default)]
+    level: u64,
+    #[serde(default)]
+    text: String,
+    #[serde(default)]
+    msg: String,
+    #[serde(default)]
+    fields: Vec<serde_json::Value>,
+}
+
+struct Build {
+    name: String,
+    phase: Option<String>,
+    last_line: Option<String>,
+}
+
+#[derive(Default)]
+struct Progress {
+    /// Activity types of running activities, by id
+    activities: HashMap<u64, u64>,
+    builds: HashMap<u64, Build>,
+    /// Bytes transferred per file transfer, by id
+    transfers: HashMap<u64, u64>,
+    builds_done: u64,
+    builds_expected: u64,
+    paths_done: u64,
+    paths_expected: u64,
+}
+
+impl Progress {
+    /// Applies an event, returning a message to print above the progress line.
+    fn handle(&mut self, event: Event) -> Option<String> {
+        match event.action.as_str() {
+            "start" => {
+                self.activities.insert(event.id, event.kind);
+                if event.kind == ACT_BUILD {
+                    let name = event
+                        .fields
+                        .first()
+                        .and_then(|field| field.as_str())
+                        .map(derivation_name)
+                        .unwrap_or_else(|| event.text.clone());
+                    self.builds.insert(
+                        event.id,
+                        Build {
+                            name,
+                            phase: None,
+                            last_line: None,
+                        },
+                    );
+                }
+                None
+            }
+            "stop" => {
+                self.activities.remove(&event.id);
+                self.builds.remove(&event.id);
+                None
+            }
+            "result" => {
+                match event.kind {
+                    RES_SET_PHASE => {
+                        if let Some(build) = self.builds.get_mut(&event.id) {
+                            build.phase = event.fields.first().and_then(field_string);
+                        }
+                    }
+                    RES_BUILD_LOG_LINE => {
+                        if let Some(build) = self.builds.get_mut(&event.id) {
+                            build.last_line = event.fields.first().and_then(field_string);
+                        }
+                    }
+                    RES_PROGRESS => self.progress(event.id, &event.fields),
+                    _ => {}
+                }
+                None
+            }
+            "msg" if event.level <= MAX_MESSAGE_LEVEL => Some(event.msg),
+            _ => None,
+        }
+    }
+
+    fn progress(&mut self, id: u64, fields: &[serde_json::Value]) {
+        let number = |i: usize| fields.get(i).and_then(|field| field.as_u64()).unwrap_or(0);
+        match self.activities.get(&id) {
+            Some(&ACT_BUILDS) => {
+                self.builds_done = number(0);
+                self.builds_expected = number(1);
+            }
+            Some(&ACT_COPY_PATHS) => {
+                self.paths_done = number(0);
+                self.paths_expected = number(1);
+            }
+            Some(&ACT_FILE_TRANSFER) => {
+                self.transfers.insert(id, number(0));
+            }
+            _ => {}
+        }
+    }
+
+    fn render(&self, width: usize) -> String {
+        let mut parts = Vec::new();
+        if self.builds_expected > 0 {
+            parts.push(format!(
+                "built {}/{}",
+                self.builds_done, self.builds_expected
+            ));
+        }
+        if self.paths_expected > 0 {
+            parts.push(format!(
+                "fetched {}/{}",
+                self.paths_done, self.paths_expected
+            ));
+        }
+        let bytes: u64 = self.transfers.values().sum();
+        if bytes > 0 {
+            parts.push(format_size(bytes));
+        }
+
+        let mut line = if parts.is_empty() {
+            String::new()
+        } else {
+            format!("[{}] ", parts.join(", "))
+        };
+        // Show the build that started most recently
+        if let Some(build) = self
+            .builds
+            .iter()
+            .max_by_key(|(id, _)| **id)
+            .map(|(_, b)| b)
+        {
+            line.push_str(&format!("building {}", build.name));
+            if let Some(phase) = &build.phase {
+                line.push_str(&format!(" ({})", phase));
+            }
+            if let Some(last_line) = &build.last_line {
+                line.push_str(&format!(": {}", last_line.trim()));
+            }
+        }
+        line.trim_end()
+            .chars()
+            .take(width.saturating_sub(1))
+            .collect()
+    }
+}
+
+/// Runs `command`, which must log in nix's internal JSON format to stderr,
+/// showing a single updating progress line on stderr.
+pub fn run(mut command: Command) -> io::Result<ExitStatus> {
+    let mut child = command.stderr(Stdio::piped()).spawn()?;
+    let stderr = child
+        .stderr
+        .take()
+        .ok_or_else(|| io::Error::other("failed to capture stderr"))?;
+    let width = std::env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+        .unwrap_or(DEFAULT_WIDTH);
+
+    let mut progress = Progress::default();
+    let mut out = io::stderr();
+    for line in BufReader::new(stderr).split(b'\n') {
+        let line = String::from_utf8_lossy(&line?).into_owned();
+        let message = match line.strip_prefix(EVENT_PREFIX) {
+            Some(json) => match serde_json::from_str::<Event>(json) {
+                Ok(event) => progress.handle(event),
+                Err(_) => Some(line),
+            },
+            None => Some(line),
+        };
+
+        if let Some(message) = message {
+            write!(out, "\r\x1b[K{}\n", message)?;
+        }
+        write!(out, "\r\x1b[K{}", progress.render(width))?;
+        out.flush()?;
+    }
+    write!(out, "\r\x1b[K")?;
+    out.flush()?;
+
+    child.wait()
+}
+
+fn field_string(field: &serde_json::Value) -> Option<String> {
+    field.as_str().map(str::to_string)
+}
+
+/// Turns `/nix/store/<hash>-name.drv` into `name`.
+fn derivation_name(path: &str) -> String {
+    let base = path.rsplit('/').next().unwrap_or(path);
+    let base = base.strip_suffix(".drv").unwrap_or(base);
+    match base.split_once('-') {
+        Some((_, name)) => name.to_string(),
+        None => base.to_string(),
+    }
+}
+
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 4] = ["B", "KiB", "MiB", "GiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} {}", bytes, UNITS[0])
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
@@ -0,0 +1,204 @@
+//! Conversion of JSON, TOML and YAML settings files to Nix attribute sets.
+
+use super::editor::{self, Change, EditError};
+use serde_json::Value;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+/// Arrays of scalars rendered on one line as long as they stay this short.
+const INLINE_LIST_WIDTH: usize = 60;
+
+/// Reads a settings file, choosing the format from its extension.
+pub fn load(path: &Path) -> io::Result<Value> {
+    let content = fs::read_to_string(path)?;
+    let extension = path
+        .extension()
+        .and_then(|ext| ext.to_str())
+        .unwrap_or_default()
+        .to_ascii_lowercase();
+
+    match extension.as_str() {
+        "json" => serde_json::from_str(&content).map_err(io::Error::from),
+        "toml" => content
+            .parse::<toml::Value>()
+            .map(toml_to_json)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        "yaml" | "yml" => serde_yaml::from_str(&content)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        _ => Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!(
+                "unsupported settings format '{}' (expected .json, .toml, .yaml or .yml)",
+                extension
+            ),
+        )),
+    }
+}
+
+fn toml_to_json(value: toml::Value) -> Value {
+    match value {
+        toml::Value::String(s) => Value::String(s),
+        toml::Value::Integer(i) => Value::from(i),
+        toml::Value::Float(f) => Value::from(f),
+        toml::Value::Boolean(b) => Value::Bool(b),
+        toml::Value::Datetime(dt) => Value::String(dt.to_string()),
+        toml::Value::Array(items) => Value::Array(items.into_iter().map(toml_to_json).collect()),
+        toml::Value::Table(table) => Value::Object(
+            table
+                .into_iter()
+                .map(|(key, value)| (key, toml_to_json(value)))
+                .collect(),
+        ),
+    }
+}
+
+/// Renders `value` as a Nix expression, with nested sets and long lists
+/// indented by two spaces per level.
+pub fn to_nix(value: &Value) -> String {
+    match value {
+        Value::Null => "null".to_string(),
+        Value::Bool(b) => b.to_string(),
+        Value::Number(n) => number_to_nix(n),
+        Value::String(s) => editor::quote(s),
+        Value::Array(items) if items.is_empty() => "[ ]".to_string(),
+        Value::Array(items) => {
+            let items: Vec<String> = items.iter().map(to_nix).collect();
+            let inline = format!("[ {} ]", items.join(" "));
+            if inline.len() <= INLINE_LIST_WIDTH && !inline.contains('\n') {
+                return inline;
+            }
+            let body: Vec<String> = items.iter().map(|item| indent(item)).collect();
+            format!("[\n{}\n]", body.join("\n"))
+        }
+        Value::Object(map) if map.is_empty() => "{ }".to_string(),
+        Value::Object(map) => {
+            let body: Vec<String> = map
+                .iter()
+                .map(|(key, value)| {
+                    indent(&format!("{} = {};", editor::attr_name(key), to_nix(value)))
+                })
+                .collect();
+            format!("{{\n{}\n}}", body.join("\n"))
+        }
+    }
+}
+
+/// Nix floats need a decimal point, also before an exponent, and negative
+/// numbers need parentheses to be list elements.
+pub fn number_to_nix(number: &serde_json::Number) -> String {
+    let mut text = number.to_string();
+    if number.is_f64() {
+        let (mantissa, exponent) = match text.split_once(['e', 'E']) {
+            Some((mantissa, exponent)) => (mantissa.to_string(), Some(exponent.to_string())),
+            None => (text.clone(), None),
+        };
+        let mantissa = if mantissa.contains('.') {
+            mantissa
+        } else {
+            format!("{}.0", mantissa)
+        };
+        text = match exponent {
+            Some(exponent) => format!("{}e{}", mantissa, exponent),
+            None => mantissa,
+        };
+    }
+    if text.starts_with('-') {
+        format!("({})", text)
+    } else {
+        text
+    }
+}
+
+fn indent(text: &str) -> String {
+    text.lines()
+        .map(|line| format!("  {}", line))
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Writes `value` to `path` in the module. Where the module already has
+/// bindings below `path`, attribute sets are merged key by key so settings
+/// that are not in `value` stay in place; everything else is replaced.
+pub fn merge(content: &str, path: &str, value: &Value) -> Result<(String, Change), EditError> {
+    let Value::Object(map) = value else {
+        return editor::set_option(content, path, &to_nix(value));
+    };
+    if map.is_empty() || !has_nested_bindings(content, path)? {
+        return editor::set_option(content, path, &to_nix(value));
+    }
+
+    let mut names = editor::split_path(path);
+    let mut content = content.to_string();
+    let mut result = Change::Unchanged;
+    for (key, value) in map {
+        names.push(key.clone());
+        let (updated, change) = merge(&content, &editor::join_path(&names), value)?;
+        names.pop();
+        content = updated;
+        if change != Change::Unchanged {
+            result = Change::Updated;
+        }
+    }
+    Ok((content, result))
+}
+
+fn has_nested_bindings(content: &str, path: &str) -> Result<bool, EditError> {
+    let target = editor::split_path(path);
+    Ok(editor::bindings(content)?.iter().any(|(binding, _)| {
+        let names = editor::split_path(binding);
+        names.len() > target.len() && names.starts_with(&target)
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn assert_valid(nix: &str) {
+        editor::check_value(nix).unwrap_or_else(|e| panic!("{}: {}", nix, e));
+    }
+
+    #[test]
+    fn negative_numbers_are_parenthesized() {
+        let nix = to_nix(&json!({ "ports": [80, -1], "offset": -2.5 }));
+        assert!(nix.contains("ports = [ 80 (-1) ];"), "{}", nix);
+        assert!(nix.contains("offset = (-2.5);"), "{}", nix);
+        assert_valid(&nix);
+    }
+
+    #[test]
+    fn floats_keep_a_decimal_point() {
+        assert_eq!(to_nix(&json!(1e-7)), "1.0e-7");
+        assert_eq!(to_nix(&json!(1e100)), "1.0e100");
+        assert_eq!(to_nix(&json!(2.0)), "2.0");
+        assert_eq!(to_nix(&json!(-1.5e-9)), "(-1.5e-9)");
+        assert_valid(&to_nix(&json!([1e-7, 1e100, 0.5])));
+    }
+
+    #[test]
+    fn nested_objects_are_indented() {
+        let nix = to_nix(&json!({ "server": { "http": { "port": 3000 } } }));
+        assert_eq!(
+            nix,
+            "{\n  server = {\n    http = {\n      port = 3000;\n    };\n  };\n}"
+        );
+        assert_valid(&nix);
+    }
+
+    #[test]
+    fn keys_are_quoted_where_needed() {
+        let nix = to_nix(&json!({
+            "net.ipv4.ip_forward": 1,
+            "with": true,
+            "1st": "a",
+            "plain-name": null,
+        }));
+        assert!(nix.contains("\"net.ipv4.ip_forward\" = 1;"), "{}", nix);
+        assert!(nix.contains("\"with\" = true;"), "{}", nix);
+        assert!(nix.contains("\"1st\" = \"a\";"), "{}", nix);
+        assert!(nix.contains("plain-name = null;"), "{}", nix);
+        assert_valid(&nix);
+    }
+}
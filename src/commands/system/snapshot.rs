@@ -0,0 +1,147 @@
+//! Named copies of the whole configuration, taken on request as restore
+//! points.
+
+use crate::utils::privilege::Privilege;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path::{Path, PathBuf};
+
+const INDEX_FILE: &str = "index.json";
+
+/// A snapshot's files are stored in a directory named after its label; file
+/// `n` of the list as `<label>/<n>`. Snapshots may hold secrets from the
+/// configuration root, so the directories are private to their owner and the
+/// copies readable by it only.
+#[derive(Serialize, Deserialize)]
+pub struct Snapshot {
+    pub label: String,
+    pub created: DateTime<Utc>,
+    /// The system generation current when the snapshot was taken
+    pub generation: Option<u32>,
+    pub files: Vec<PathBuf>,
+}
+
+pub struct SnapshotStore {
+    dir: PathBuf,
+    privilege: Privilege,
+}
+
+impl SnapshotStore {
+    pub fn new(dir: PathBuf, privilege: Privilege) -> Self {
+        SnapshotStore { dir, privilege }
+    }
+
+    /// Lists snapshots, oldest first.
+    pub fn list(&self) -> io::Result<Vec<Snapshot>> {
+        let labels = match self.privilege.list_dir(&self.dir) {
+            Ok(labels) => labels,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+            Err(e) => return Err(e),
+        };
+        let mut snapshots = Vec::new();
+        for label in labels {
+            if let Some(snapshot) = self.find(&label)? {
+                snapshots.push(snapshot);
+            }
+        }
+        snapshots.sort_by_key(|snapshot: &Snapshot| snapshot.created);
+        Ok(snapshots)
+    }
+
+    pub fn find(&self, label: &str) -> io::Result<Option<Snapshot>> {
+        let content = match self.privilege.read(&self.dir.join(label).join(INDEX_FILE)) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        serde_json::from_slice(&content)
+            .map(Some)
+            .map_err(io::Error::from)
+    }
+
+    /// Stores `files` (path and content) under `label`, replacing any
+    /// snapshot with the same label.
+    pub fn create(
+        &self,
+        label: &str,
+        generation: Option<u32>,
+        files: &[(PathBuf, Vec<u8>)],
+    ) -> io::Result<Snapshot> {
+        let dir = self.dir.join(label);
+        match self.privilege.remove_dir_all(&dir) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+        self.privilege.create_private_dir(&self.dir)?;
+        self.privilege.create_private_dir(&dir)?;
+
+        for (i, (_, content)) in files.iter().enumerate() {
+            self.privilege
+                .write_private(&dir.join(i.to_string()), content)?;
+        }
+        let snapshot = Snapshot {
+            label: label.to_string(),
+            created: Utc::now(),
+            generation,
+            files: files.iter().map(|(path, _)| path.clone()).collect(),
+        };
+        self.privilege.write_private(
+            &dir.join(INDEX_FILE),
+            serde_json::to_string_pretty(&snapshot)?.as_bytes(),
+        )?;
+        Ok(snapshot)
+    }
+
+    /// Reads the snapshot's files with their content.
+    pub fn read(&self, snapshot: &Snapshot) -> io::Result<Vec<(PathBuf, Vec<u8>)>> {
+        let dir = self.dir.join(&snapshot.label);
+        snapshot
+            .files
+            .iter()
+            .enumerate()
+            .map(|(i, path)| {
+                self.privilege
+                    .read(&dir.join(i.to_string()))
+                    .map(|content| (path.clone(), content))
+            })
+            .collect()
+    }
+}
+
+/// Checks that `label` can be used as a directory name.
+pub fn valid_label(label: &str) -> bool {
+    !label.is_empty()
+        && !label.starts_with('.')
+        && !label.contains(['/', '\\'])
+        && !Path::new(label).is_absolute()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    fn mode(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn snapshots_are_private() {
+        let dir = std::env::temp_dir().join(format!("nx-snapshot-test-{}", std::process::id()));
+        let store = SnapshotStore::new(dir.clone(), Privilege::None);
+        let files = vec![(PathBuf::from("/etc/nixos/secret.key"), b"key".to_vec())];
+        store.create("before", None, &files).unwrap();
+
+        assert_eq!(mode(&dir), 0o700);
+        assert_eq!(mode(&dir.join("before")), 0o700);
+        assert_eq!(mode(&dir.join("before").join("0")), 0o600);
+        assert_eq!(mode(&dir.join("before").join(INDEX_FILE)), 0o600);
+
+        let snapshot = store.find("before").unwrap().unwrap();
+        assert_eq!(store.read(&snapshot).unwrap(), files);
+        assert_eq!(store.list().unwrap().len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
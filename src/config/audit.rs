@@ -0,0 +1,120 @@
+//! The append-only log of operations nx performed.
+
+use crate::utils::lock::flock;
+use chrono::{DateTime, Utc};
+use directories::ProjectDirs;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::PathBuf;
+
+const LOG_FILE: &str = "operations.jsonl";
+
+#[derive(Serialize, Deserialize)]
+pub struct Entry {
+    pub id: u64,
+    pub timestamp: DateTime<Utc>,
+    pub user: String,
+    pub command: Vec<String>,
+    /// Configuration files whose content the operation changed
+    #[serde(default)]
+    pub files: Vec<FileChange>,
+    #[serde(default)]
+    pub profile: Option<ProfileChange>,
+    /// Set when a rebuild switched to another system generation
+    #[serde(default)]
+    pub generation: Option<GenerationChange>,
+    pub exit_code: i32,
+    /// The entry this operation undid
+    #[serde(default)]
+    pub undoes: Option<u64>,
+}
+
+/// Content hashes of a file before and after an operation; `None` when the
+/// file did not exist.
+#[derive(Serialize, Deserialize)]
+pub struct FileChange {
+    pub path: PathBuf,
+    pub before: Option<String>,
+    pub after: Option<String>,
+    /// The previous content of files nx does not back up, such as the
+    /// pending-change journal
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub previous: Option<String>,
+}
+
+/// Changes to the user's nix profile.
+#[derive(Serialize, Deserialize)]
+pub struct ProfileChange {
+    pub generation_before: Option<u32>,
+    pub generation_after: Option<u32>,
+    pub installed: Vec<String>,
+    pub removed: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GenerationChange {
+    pub before: Option<u32>,
+    pub after: Option<u32>,
+}
+
+/// Reads all entries, oldest first. Lines that do not parse are skipped.
+pub fn entries() -> io::Result<Vec<Entry>> {
+    let Some(path) = get_log_path() else {
+        return Ok(Vec::new());
+    };
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    Ok(parse(&fs::read_to_string(path)?))
+}
+
+fn parse(content: &str) -> Vec<Entry> {
+    content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+/// Appends `entry`, numbering it after the last entry in the log. The log
+/// stays locked in between, so concurrent operations get distinct ids.
+pub fn append(mut entry: Entry) -> io::Result<Entry> {
+    let path = get_log_path()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "no state directory"))?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new()
+        .read(true)
+        .create(true)
+        .append(true)
+        .open(&path)?;
+    flock(&file, libc::LOCK_EX)?;
+
+    let mut content = String::new();
+    file.read_to_string(&mut content)?;
+    entry.id = parse(&content).last().map_or(1, |last| last.id + 1);
+
+    let mut line = serde_json::to_string(&entry)?;
+    line.push('\n');
+    file.write_all(line.as_bytes())?;
+    Ok(entry)
+}
+
+/// Hex SHA-256 of `content`, identifying file versions in the log.
+pub fn content_hash(content: &[u8]) -> String {
+    Sha256::digest(content)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+fn get_log_path() -> Option<PathBuf> {
+    ProjectDirs::from("com", "nx", "nx-wrapper").map(|proj_dirs| {
+        proj_dirs
+            .state_dir()
+            .unwrap_or_else(|| proj_dirs.data_local_dir())
+            .join(LOG_FILE)
+    })
+}
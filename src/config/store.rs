@@ -1,11 +1,55 @@
+use crate::utils::privilege::Privilege;
 use directories::ProjectDirs;
 use serde::{Deserialize, Serialize};
 use std::fs;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+use std::process::Command;
 
-#[derive(Serialize, Deserialize, Default)]
+const DEFAULT_NIXOS_ROOT: &str = "/etc/nixos";
+const DEFAULT_NIXOS_FILE: &str = "configuration.nix";
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
 pub struct Config {
     pub allow_unfree: bool,
+    /// Number of configuration backups to keep (0 disables backups)
+    pub backup_retention: usize,
+    /// Directory holding the NixOS configuration
+    pub nixos_root: PathBuf,
+    /// File nx edits, relative to `nixos_root`
+    pub nixos_file: PathBuf,
+    /// Whether `nixos_root` is a flake; detected from `flake.nix` when unset
+    pub nixos_flake: Option<bool>,
+    /// Host to build from the flake; defaults to the machine's hostname
+    pub nixos_host: Option<String>,
+    /// Send permanent edits to the nx-managed module instead of hand-written files
+    pub managed_module: bool,
+    /// NixOS options database to use instead of building it from the configuration
+    pub options_json: Option<PathBuf>,
+    /// How to gain root for rebuilds and configuration writes
+    pub privilege: Privilege,
+    /// Commit each configuration edit to the git repository holding it
+    pub git_commit: bool,
+    /// Tag the commit a generation was built from after rebuilds nx runs
+    pub git_tag: bool,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            allow_unfree: false,
+            backup_retention: 10,
+            nixos_root: PathBuf::from(DEFAULT_NIXOS_ROOT),
+            nixos_file: PathBuf::from(DEFAULT_NIXOS_FILE),
+            nixos_flake: None,
+            nixos_host: None,
+            managed_module: false,
+            options_json: None,
+            privilege: Privilege::default(),
+            git_commit: false,
+            git_tag: false,
+        }
+    }
 }
 
 impl Config {
@@ -20,6 +64,25 @@ impl Config {
         Config::default()
     }
 
+    /// The NixOS configuration file nx edits.
+    pub fn nixos_config_path(&self) -> PathBuf {
+        self.nixos_root.join(&self.nixos_file)
+    }
+
+    pub fn is_flake(&self) -> bool {
+        self.nixos_flake
+            .unwrap_or_else(|| self.nixos_root.join("flake.nix").exists())
+    }
+
+    pub fn flake_host(&self) -> String {
+        self.nixos_host.clone().unwrap_or_else(hostname)
+    }
+
+    /// The `<root>#<host>` reference passed to `nixos-rebuild --flake`.
+    pub fn flake_ref(&self) -> String {
+        format!("{}#{}", self.nixos_root.display(), self.flake_host())
+    }
+
     pub fn save(&self) {
         if let Some(config_path) = get_config_path() {
             if let Some(parent) = config_path.parent() {
@@ -37,3 +100,13 @@ fn get_config_path() -> Option<PathBuf> {
     ProjectDirs::from("com", "nx", "nx-wrapper")
         .map(|proj_dirs| proj_dirs.config_dir().to_path_buf().join("config.json"))
 }
+
+fn hostname() -> String {
+    if let Ok(name) = fs::read_to_string(Path::new("/proc/sys/kernel/hostname")) {
+        return name.trim().to_string();
+    }
+    Command::new("hostname")
+        .output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_default()
+}
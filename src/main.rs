@@ -9,12 +9,16 @@ use cli::{Cli, Commands};
 fn main() {
     let cli = Cli::parse();
     let mut config = config::store::Config::load();
+    let recorder = commands::log::should_record(&cli.command)
+        .then(|| commands::log::Recorder::start(&cli.command, &config));
 
     let exit_code = match cli.command {
-        Commands::Install { package } => {
-            commands::install(&package, &cli.passthrough_args, &config)
-        }
-        Commands::Config { allow_unfree } => commands::config_execute(allow_unfree, &mut config),
+        Commands::Install {
+            package,
+            wait,
+            allow_dirty,
+        } => commands::install(&package, wait, allow_dirty, &cli.passthrough_args, &config),
+        Commands::Config { options } => commands::config_execute(options, &mut config),
         Commands::GarbageCollect {
             delete_old,
             foreground,
@@ -32,9 +36,17 @@ fn main() {
             commands::search_execute(&query, &cli.passthrough_args, &config)
         }
         Commands::List => commands::list_execute(&cli.passthrough_args, &config),
-        Commands::System { command } => {
-            commands::system_execute(command, &cli.passthrough_args, &config)
-        }
+        Commands::System {
+            command,
+            wait,
+            allow_dirty,
+        } => commands::system_execute(command, wait, allow_dirty, &cli.passthrough_args, &config),
+        Commands::Log { limit } => commands::log_execute(limit),
+        Commands::Undo {
+            id,
+            wait,
+            allow_dirty,
+        } => commands::undo(id, wait, allow_dirty, &config),
         Commands::Generate { url, output } => {
             tokio::runtime::Runtime::new()
                 .unwrap()
@@ -47,5 +59,8 @@ fn main() {
         }
     };
 
+    if let Some(recorder) = recorder {
+        recorder.finish(exit_code, None, &config);
+    }
     std::process::exit(exit_code);
 }
@@ -0,0 +1,114 @@
+use std::ffi::{CString, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+/// Replaces the file at `path` without ever leaving it partially written.
+///
+/// The content goes to a temporary file in the same directory, is synced to
+/// disk, and is then renamed over the original, keeping its permissions.
+pub fn atomic_write(path: &Path, content: &[u8]) -> io::Result<()> {
+    write_atomically(path, content, None)
+}
+
+/// Like `atomic_write`, but the file ends up readable and writable by its
+/// owner only, whatever its previous mode.
+pub fn atomic_write_private(path: &Path, content: &[u8]) -> io::Result<()> {
+    write_atomically(path, content, Some(0o600))
+}
+
+fn write_atomically(path: &Path, content: &[u8], mode: Option<u32>) -> io::Result<()> {
+    let dir = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let name = path
+        .file_name()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "path has no file name"))?;
+    let temp_path = dir.join(format!(
+        ".{}.nx-tmp-{}",
+        name.to_string_lossy(),
+        std::process::id()
+    ));
+
+    let result = write_and_rename(&temp_path, path, content, mode);
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result?;
+
+    // Persist the rename itself
+    if let Ok(dir) = File::open(dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+fn write_and_rename(
+    temp_path: &Path,
+    path: &Path,
+    content: &[u8],
+    mode: Option<u32>,
+) -> io::Result<()> {
+    let mut file = OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(mode.unwrap_or(0o666))
+        .open(temp_path)?;
+    match mode {
+        Some(mode) => file.set_permissions(fs::Permissions::from_mode(mode))?,
+        None => {
+            if let Ok(metadata) = fs::metadata(path) {
+                file.set_permissions(metadata.permissions())?;
+            }
+        }
+    }
+    file.write_all(content)?;
+    file.sync_all()?;
+    fs::rename(temp_path, path)
+}
+
+/// Creates the directory at `path` and any missing parents, and restricts it
+/// to its owner, also when it already existed.
+pub fn create_private_dir(path: &Path) -> io::Result<()> {
+    fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o700)
+        .create(path)?;
+    fs::set_permissions(path, fs::Permissions::from_mode(0o700))
+}
+
+/// Creates a new directory only the current user can access, named
+/// `<prefix>XXXXXX` in the system temporary directory.
+pub fn private_temp_dir(prefix: &str) -> io::Result<PathBuf> {
+    let template = std::env::temp_dir().join(format!("{}XXXXXX", prefix));
+    let template = CString::new(template.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let mut buffer = template.into_bytes_with_nul();
+    // SAFETY: `buffer` is a writable, NUL-terminated template that mkdtemp
+    // fills in place
+    if unsafe { libc::mkdtemp(buffer.as_mut_ptr().cast()) }.is_null() {
+        return Err(io::Error::last_os_error());
+    }
+    buffer.pop();
+    Ok(PathBuf::from(OsString::from_vec(buffer)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn private_temp_dir_is_new_and_owner_only() {
+        let first = private_temp_dir("nx-test-").unwrap();
+        let second = private_temp_dir("nx-test-").unwrap();
+        assert_ne!(first, second);
+        let mode = fs::metadata(&first).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o700);
+        fs::remove_dir(first).unwrap();
+        fs::remove_dir(second).unwrap();
+    }
+}
@@ -0,0 +1,178 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+
+/// An advisory lock serializing nx operations, released when dropped.
+pub struct OperationLock {
+    _file: File,
+}
+
+impl OperationLock {
+    /// Takes the lock at `path`, creating the file in place if needed. If
+    /// another process holds it, either fails with a message naming that
+    /// process or, with `wait`, blocks until it is released.
+    pub fn acquire(path: &Path, wait: bool) -> io::Result<Self> {
+        let (mut file, writable) = open(path)?;
+
+        match flock(&file, libc::LOCK_EX | libc::LOCK_NB) {
+            Ok(()) => {}
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                let holder = read_holder(&mut file);
+                if !wait {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WouldBlock,
+                        format!(
+                            "another nx operation ({}) is in progress; use --wait to wait for it",
+                            holder
+                        ),
+                    ));
+                }
+                println!("Waiting for another nx operation ({}) to finish...", holder);
+                flock(&file, libc::LOCK_EX)?;
+            }
+            Err(e) => return Err(e),
+        }
+
+        if !writable {
+            return Ok(OperationLock { _file: file });
+        }
+
+        // Record who holds the lock for anyone who has to wait on it
+        let command = std::env::args().collect::<Vec<_>>().join(" ");
+        file.set_len(0)?;
+        file.seek(SeekFrom::Start(0))?;
+        writeln!(file, "{}\n{}", std::process::id(), command)?;
+        file.flush()?;
+
+        Ok(OperationLock { _file: file })
+    }
+}
+
+/// Applies `flock(2)` to `file`, retrying when interrupted by a signal. The
+/// lock goes away when the file is closed.
+pub fn flock(file: &File, operation: libc::c_int) -> io::Result<()> {
+    loop {
+        // SAFETY: the descriptor belongs to `file`, which outlives the call
+        if unsafe { libc::flock(file.as_raw_fd(), operation) } == 0 {
+            return Ok(());
+        }
+        let error = io::Error::last_os_error();
+        if error.kind() != io::ErrorKind::Interrupted {
+            return Err(error);
+        }
+    }
+}
+
+/// Names the process holding the lock on `file`. The kernel's lock table is
+/// authoritative; the pid recorded in the file may be stale, since holders
+/// that could only open it read-only cannot update it.
+fn read_holder(file: &mut File) -> String {
+    if let Some(pid) = lock_owner(file) {
+        let command = fs::read(format!("/proc/{}/cmdline", pid))
+            .map(|cmdline| {
+                String::from_utf8_lossy(&cmdline)
+                    .split_terminator('\0')
+                    .collect::<Vec<_>>()
+                    .join(" ")
+            })
+            .unwrap_or_default();
+        return if command.is_empty() {
+            format!("pid {}", pid)
+        } else {
+            format!("pid {}, {}", pid, command)
+        };
+    }
+
+    let mut content = String::new();
+    let _ = file.read_to_string(&mut content);
+    let mut lines = content.lines();
+    match (lines.next(), lines.next()) {
+        (Some(pid), command) if Path::new("/proc").join(pid).exists() => match command {
+            Some(command) => format!("pid {}, {}", pid, command),
+            None => format!("pid {}", pid),
+        },
+        _ => "unknown process".to_string(),
+    }
+}
+
+/// Looks up the pid holding an flock on `file` in `/proc/locks`, whose lines
+/// read like `1: FLOCK  ADVISORY  WRITE 1234 fe:00:5678 0 EOF`.
+fn lock_owner(file: &File) -> Option<u32> {
+    let metadata = file.metadata().ok()?;
+    // Linux's encoding of device numbers, as in glibc's major() and minor()
+    let dev = metadata.dev();
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    let id = format!("{:02x}:{:02x}:{}", major, minor, metadata.ino());
+    fs::read_to_string("/proc/locks")
+        .ok()?
+        .lines()
+        .filter(|line| !line.contains("->"))
+        .find_map(|line| {
+            let fields: Vec<&str> = line.split_whitespace().collect();
+            match fields.as_slice() {
+                [_, "FLOCK", _, _, pid, lock_id, ..] if *lock_id == id => pid.parse().ok(),
+                _ => None,
+            }
+        })
+}
+
+/// Opens the lock file for writing if possible, so the holder can be
+/// recorded, and read-only otherwise (e.g. a root-owned file taken by a normal
+/// user); both can be locked.
+fn open(path: &Path) -> io::Result<(File, bool)> {
+    match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)
+    {
+        Ok(file) => Ok((file, true)),
+        Err(e) if e.kind() == io::ErrorKind::PermissionDenied && path.exists() => {
+            Ok((File::open(path)?, false))
+        }
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_acquire_fails_while_held() {
+        let path = std::env::temp_dir().join(format!("nx-lock-test-{}", std::process::id()));
+        let lock = OperationLock::acquire(&path, false).unwrap();
+        let error = OperationLock::acquire(&path, false).err().unwrap();
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        assert!(error.to_string().contains(&std::process::id().to_string()));
+
+        drop(lock);
+        assert!(OperationLock::acquire(&path, false).is_ok());
+        let _ = std::fs::remove_file(path);
+    }
+
+    #[test]
+    fn holder_comes_from_the_kernel_not_a_stale_file() {
+        let path = std::env::temp_dir().join(format!("nx-lock-stale-{}", std::process::id()));
+        std::fs::write(&path, "999999999\nnx sys pkg old\n").unwrap();
+        // Held without recording, like a holder that opened the file read-only
+        let holder = File::open(&path).unwrap();
+        flock(&holder, libc::LOCK_EX).unwrap();
+
+        let error = OperationLock::acquire(&path, false).err().unwrap();
+        let message = error.to_string();
+        assert!(!message.contains("999999999"), "{}", message);
+        assert!(
+            message.contains(&format!("pid {}", std::process::id())),
+            "{}",
+            message
+        );
+
+        drop(holder);
+        let _ = std::fs::remove_file(path);
+    }
+}
@@ -1,2 +1,6 @@
 pub mod command;
+pub mod file;
+pub mod lock;
 pub mod package;
+pub mod privilege;
+pub mod profile;
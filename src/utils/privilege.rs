@@ -0,0 +1,243 @@
+use crate::utils::file::{atomic_write, atomic_write_private, create_private_dir};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+
+/// Replaces `$1` atomically with stdin, keeping the mode of an existing file
+/// and syncing the content to disk before the rename, like `atomic_write`.
+const WRITE_SCRIPT: &str = r#"t="$1.nx-tmp-$$"; cat > "$t" && { chmod --reference="$1" "$t" 2>/dev/null || true; } && sync "$t" && mv -f "$t" "$1" && sync "$(dirname "$1")""#;
+/// Like `WRITE_SCRIPT`, but the file is created readable by root only.
+const PRIVATE_WRITE_SCRIPT: &str = r#"umask 077; t="$1.nx-tmp-$$"; cat > "$t" && chmod 600 "$t" && sync "$t" && mv -f "$t" "$1" && sync "$(dirname "$1")""#;
+/// Prints `$1`, exiting with `MISSING_STATUS` if it does not exist.
+const READ_SCRIPT: &str = r#"[ -e "$1" ] || exit 3; exec cat "$1""#;
+/// Prints the names of the entries in directory `$1` not starting with `.`,
+/// each followed by a NUL byte.
+const LIST_SCRIPT: &str = r#"[ -d "$1" ] || exit 3; for f in "$1"/*; do [ -e "$f" ] && printf '%s\0' "${f##*/}"; done; true"#;
+const MISSING_STATUS: i32 = 3;
+
+/// How nx gains root for rebuilds and for writing the system configuration.
+#[derive(Serialize, Deserialize, ValueEnum, Clone, Copy, PartialEq, Eq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Privilege {
+    #[default]
+    Sudo,
+    Doas,
+    Run0,
+    Pkexec,
+    /// Run everything as the current user
+    None,
+}
+
+impl fmt::Display for Privilege {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.to_possible_value() {
+            Some(value) => write!(f, "{}", value.get_name()),
+            None => write!(f, "{:?}", self),
+        }
+    }
+}
+
+impl Privilege {
+    /// Builds a command running `program` as root. When already root, or with
+    /// `Privilege::None`, the program runs directly.
+    pub fn command(self, program: &str) -> Command {
+        let tool = match self {
+            _ if is_root() => return Command::new(program),
+            Privilege::Sudo => "sudo",
+            Privilege::Doas => "doas",
+            Privilege::Run0 => "run0",
+            Privilege::Pkexec => "pkexec",
+            Privilege::None => return Command::new(program),
+        };
+        // pkexec and run0 reset PATH, so pass the program's full path
+        let mut command = Command::new(tool);
+        command.arg(resolve(program));
+        command
+    }
+
+    /// Renders `command` the way a user would type it to run it as root.
+    pub fn command_line(self, command: &str) -> String {
+        match self {
+            _ if is_root() => command.to_string(),
+            Privilege::None => command.to_string(),
+            privilege => format!("{} {}", privilege, command),
+        }
+    }
+
+    /// Reads the file at `path`, through the privilege tool if the current
+    /// user may not read it.
+    pub fn read(self, path: &Path) -> io::Result<Vec<u8>> {
+        match fs::read(path) {
+            Err(e) if self.should_elevate(&e) => self.read_script(READ_SCRIPT, path),
+            result => result,
+        }
+    }
+
+    /// Lists the names of the entries in the directory at `path`, leaving
+    /// out hidden ones, through the privilege tool if the current user may
+    /// not read it.
+    pub fn list_dir(self, path: &Path) -> io::Result<Vec<String>> {
+        let names = match fs::read_dir(path) {
+            Ok(entries) => entries
+                .map(|entry| entry.map(|entry| entry.file_name().to_string_lossy().into_owned()))
+                .collect::<io::Result<Vec<_>>>()?,
+            Err(e) if self.should_elevate(&e) => {
+                let output = self.read_script(LIST_SCRIPT, path)?;
+                String::from_utf8_lossy(&output)
+                    .split_terminator('\0')
+                    .map(str::to_string)
+                    .collect()
+            }
+            Err(e) => return Err(e),
+        };
+        Ok(names
+            .into_iter()
+            .filter(|name| !name.starts_with('.'))
+            .collect())
+    }
+
+    /// Writes `content` to `path` atomically, through an elevated helper if
+    /// the current user may not write there.
+    pub fn write(self, path: &Path, content: &[u8]) -> io::Result<()> {
+        match atomic_write(path, content) {
+            Err(e) if self.should_elevate(&e) => {
+                self.run_script(WRITE_SCRIPT, &[path], Some(content))
+            }
+            result => result,
+        }
+    }
+
+    /// Writes `content` to `path` atomically, leaving the file readable by
+    /// its owner only, for copies of files that may hold secrets.
+    pub fn write_private(self, path: &Path, content: &[u8]) -> io::Result<()> {
+        match atomic_write_private(path, content) {
+            Err(e) if self.should_elevate(&e) => {
+                self.run_script(PRIVATE_WRITE_SCRIPT, &[path], Some(content))
+            }
+            result => result,
+        }
+    }
+
+    /// Creates an empty file at `path` unless it exists, without replacing
+    /// an existing file.
+    pub fn create_file(self, path: &Path) -> io::Result<()> {
+        let result = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(path);
+        match result {
+            Err(e) if self.should_elevate(&e) => self.run_script(r#"touch "$1""#, &[path], None),
+            result => result.map(|_| ()),
+        }
+    }
+
+    pub fn remove_file(self, path: &Path) -> io::Result<()> {
+        match fs::remove_file(path) {
+            Err(e) if self.should_elevate(&e) => self.run_script(r#"rm -f "$1""#, &[path], None),
+            result => result,
+        }
+    }
+
+    pub fn create_dir_all(self, path: &Path) -> io::Result<()> {
+        match fs::create_dir_all(path) {
+            Err(e) if self.should_elevate(&e) => self.run_script(r#"mkdir -p "$1""#, &[path], None),
+            result => result,
+        }
+    }
+
+    /// Creates the directory at `path` with mode 0700, restricting it to its
+    /// owner if it already exists.
+    pub fn create_private_dir(self, path: &Path) -> io::Result<()> {
+        match create_private_dir(path) {
+            Err(e) if self.should_elevate(&e) => {
+                self.run_script(r#"mkdir -p -m 700 "$1" && chmod 700 "$1""#, &[path], None)
+            }
+            result => result,
+        }
+    }
+
+    pub fn remove_dir_all(self, path: &Path) -> io::Result<()> {
+        match fs::remove_dir_all(path) {
+            Err(e) if self.should_elevate(&e) => self.run_script(r#"rm -rf "$1""#, &[path], None),
+            result => result,
+        }
+    }
+
+    fn should_elevate(self, error: &io::Error) -> bool {
+        error.kind() == io::ErrorKind::PermissionDenied && self != Privilege::None && !is_root()
+    }
+
+    /// Runs a shell script as root with `path` as `$1` and returns its
+    /// output. A script exiting with `MISSING_STATUS` reports `NotFound`.
+    fn read_script(self, script: &str, path: &Path) -> io::Result<Vec<u8>> {
+        let output = self
+            .command("sh")
+            .args(["-c", script, "sh"])
+            .arg(path)
+            .stdin(Stdio::null())
+            .output()?;
+        match output.status.code() {
+            Some(0) => Ok(output.stdout),
+            Some(MISSING_STATUS) => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                format!("{} does not exist", path.display()),
+            )),
+            _ => Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("elevated read of {} failed", path.display()),
+            )),
+        }
+    }
+
+    /// Runs a shell script as root with `args` as `$1`, `$2`, ..., feeding
+    /// `input` to its stdin.
+    fn run_script(self, script: &str, args: &[&Path], input: Option<&[u8]>) -> io::Result<()> {
+        let mut command = self.command("sh");
+        command
+            .args(["-c", script, "sh"])
+            .args(args)
+            .stdin(if input.is_some() {
+                Stdio::piped()
+            } else {
+                Stdio::null()
+            });
+
+        let mut child = command.spawn()?;
+        if let (Some(input), Some(mut stdin)) = (input, child.stdin.take()) {
+            stdin.write_all(input)?;
+        }
+        let status = child.wait()?;
+        if !status.success() {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                format!("elevated write to {} failed", args[0].display()),
+            ));
+        }
+        Ok(())
+    }
+}
+
+fn is_root() -> bool {
+    fs::metadata("/proc/self").is_ok_and(|metadata| metadata.uid() == 0)
+}
+
+/// Finds `program` on PATH, falling back to the bare name.
+fn resolve(program: &str) -> PathBuf {
+    if program.contains('/') {
+        return PathBuf::from(program);
+    }
+    env::var_os("PATH")
+        .and_then(|paths| {
+            env::split_paths(&paths)
+                .map(|dir| dir.join(program))
+                .find(|candidate| candidate.is_file())
+        })
+        .unwrap_or_else(|| PathBuf::from(program))
+}
@@ -0,0 +1,54 @@
+use std::collections::BTreeSet;
+use std::env;
+use std::fs;
+use std::path::PathBuf;
+use std::process::Command;
+
+/// The user's nix profile at one point in time.
+pub struct ProfileState {
+    pub generation: Option<u32>,
+    pub packages: BTreeSet<String>,
+}
+
+/// Reads the current profile generation and package names, or `None` if
+/// `nix profile list` fails.
+pub fn current() -> Option<ProfileState> {
+    let output = Command::new("nix")
+        .args(["profile", "list", "--json"])
+        .output()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    let list: serde_json::Value = serde_json::from_slice(&output.stdout).ok()?;
+    let packages = match &list["elements"] {
+        // nix 2.20 and later key elements by name
+        serde_json::Value::Object(elements) => elements.keys().cloned().collect(),
+        serde_json::Value::Array(elements) => elements
+            .iter()
+            .filter_map(|element| element["attrPath"].as_str())
+            .map(|path| path.rsplit('.').next().unwrap_or(path).to_string())
+            .collect(),
+        _ => BTreeSet::new(),
+    };
+    Some(ProfileState {
+        generation: generation(),
+        packages,
+    })
+}
+
+/// The generation `~/.nix-profile` points at, from its `profile-<n>-link`
+/// target.
+fn generation() -> Option<u32> {
+    let link = PathBuf::from(env::var_os("HOME")?).join(".nix-profile");
+    let profile = fs::read_link(link).ok()?;
+    let target = fs::read_link(profile).ok()?;
+    target
+        .file_name()?
+        .to_str()?
+        .strip_suffix("-link")?
+        .rsplit_once('-')?
+        .1
+        .parse()
+        .ok()
+}